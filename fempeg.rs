@@ -36,7 +36,7 @@ import vector = vec;
 
 import float::cos;
 import i32::range;
-import io::println;
+import io::{ReaderUtil, WriterUtil, println};
 import str::from_slice;
 import result::unwrap;
 import vector::{mut_view, view};
@@ -59,6 +59,10 @@ fn abort(error: String) -> ! {
 
 const SAMPLES_PER_FRAME: uint = 1152;
 
+// Delay of the synthesis filterbank in samples: half the 512-tap window, less half a subband
+// block, plus one.
+const DECODER_DELAY: uint = 241;
+
 // Modes
 enum Mode {
     Stereo,
@@ -306,11 +310,82 @@ fn MP2Context() -> MP2Context {
     return MP2Context { constants: MP2Constants(), N: N };
 }
 
+// What to do with scale factors that fail the DAB ScF-CRC check
+enum ScfCrcMode {
+    ScfCrcOff,          // Don't check.
+    ScfCrcReuse,        // Reuse the previous frame's scale factors.
+    ScfCrcAttenuate     // Keep the scale factors, but attenuate by SCF_CRC_ATTENUATION steps.
+}
+
+// Attenuation applied to subbands with corrupt scale factors, in 2 dB scale factor steps
+const SCF_CRC_ATTENUATION: i32 = 6;
+
+// Scale factor parts actually transmitted for each scale factor selector value; the others are
+// copies. -1 marks the end.
+const SCF_TRANSMITTED: [[i32]/3]/4 = [ [ 0, 1, 2 ], [ 0, 2, -1 ], [ 0, -1, -1 ], [ 0, 1, -1 ] ];
+
+// How to fill in for a frame that can't be decoded
+enum ConcealMode {
+    ConcealRepeat,      // Repeat the previous frame, fading out over CONCEAL_FADE_FRAMES frames.
+    ConcealMute,        // Fade the previous frame out within one frame.
+    ConcealSilence      // Output silence.
+}
+
+const CONCEAL_FADE_FRAMES: uint = 4;
+
+// Emphasis field values: none, 50/15 microseconds and CCITT J.17 (2 is reserved)
+const EMPHASIS_NONE: i32 = 0;
+const EMPHASIS_50_15: i32 = 1;
+const EMPHASIS_J17: i32 = 3;
+
+// Output format of the decoder. Output is always interleaved stereo; a mono stream has the same
+// samples in both channels, and a player may play just one.
+struct AudioFormat {
+    sample_rate: i32;
+    channels: uint;
+}
+
 struct MP2Stream {
     context: &MP2Context;
     V: [[mut i32]/1024]/2;
     mut Voffs: i32;
     U: [mut i32]/512;
+
+    // Size of the last frame decoded and the bit offset within it where its audio data ended.
+    // Anything in between is ancillary data.
+    mut frame_size: uint;
+    mut audio_end_bit: uint;
+
+    // DAB ScF-CRC state: the check words for this frame, carried by the previous one, and the
+    // previous frame's scale factors (63, i.e. silence, where nothing was allocated).
+    mut scf_crc_mode: ScfCrcMode;
+    scf_crc: [mut u8]/4;
+    mut scf_crc_count: uint;
+    prev_scalefactor: [[[mut i32]/3]/32]/2;
+
+    // Error concealment state: the previous frame's subband samples, one set per granule, and
+    // the number of frames concealed in a row.
+    mut conceal_mode: ConcealMode;
+    prev_sample: [[[[mut i32]/3]/32]/2]/12;
+    mut concealed_frames: uint;
+
+    // De-emphasis filter state: whether to apply it, whether the caller applies it to its own
+    // output rather than the stream to the stereo output, the emphasis and sample rate it's set
+    // up for, its coefficients (b0, b1, a1 in 2^-30 units) and each channel's last input and
+    // output, for up to MC_OUTPUT_CHANNELS channels.
+    mut deemphasis: bool;
+    mut deemph_by_caller: bool;
+    mut deemph_emphasis: i32;
+    mut deemph_sample_rate: i32;
+    deemph_coeffs: [mut i64]/3;
+    deemph_state: [mut i32]/12;
+
+    // Format of the last frame decoded, and whether it differs from the frame before
+    mut format: AudioFormat;
+    mut format_changed: bool;
+
+    // Gain applied to the output, in 2^-16 units
+    mut gain: i64;
 }
 
 fn MP2Stream(context: &MP2Context) -> MP2Stream {
@@ -318,7 +393,25 @@ fn MP2Stream(context: &MP2Context) -> MP2Stream {
         context: context,
         V: [ [ mut 0, ..1024 ], [ mut 0, ..1024 ] ],
         Voffs: 0,
-        U: [ mut 0, ..512 ]
+        U: [ mut 0, ..512 ],
+        frame_size: 0,
+        audio_end_bit: 0,
+        scf_crc_mode: ScfCrcOff,
+        scf_crc: [ mut 0, 0, 0, 0 ],
+        scf_crc_count: 0,
+        prev_scalefactor: [ [ [ mut 63, 63, 63 ], ..32 ], [ [ mut 63, 63, 63 ], ..32 ] ],
+        conceal_mode: ConcealRepeat,
+        prev_sample: [ [ [ [ mut 0, 0, 0 ], ..32 ], [ [ mut 0, 0, 0 ], ..32 ] ], ..12 ],
+        concealed_frames: 0,
+        deemphasis: true,
+        deemph_by_caller: false,
+        deemph_emphasis: EMPHASIS_NONE,
+        deemph_sample_rate: 0,
+        deemph_coeffs: [ mut 0, 0, 0 ],
+        deemph_state: [ mut 0, ..12 ],
+        format: AudioFormat { sample_rate: 0, channels: 0 },
+        format_changed: false,
+        gain: 65536
     }
 }
 
@@ -328,6 +421,7 @@ struct Bitstream {
     mut bit_window: i32;
     mut bits_in_window: i32;
     mut frame_pos: &[u8];
+    mut bytes_past_end: uint;   // Zero bytes read after the end of the data
 }
 
 impl Bitstream {
@@ -340,6 +434,13 @@ impl Bitstream {
         self.bit_window = (self.bit_window << bit_count) & 0xffffff;
         self.bits_in_window -= bit_count;
         while self.bits_in_window < 16 {
+            // Past the end of the data, read zeros. A damaged frame may claim more bits than it
+            // holds, and the window reads two bytes ahead anyway.
+            if self.frame_pos.len() == 0 {
+                self.bytes_past_end += 1;
+                self.bits_in_window += 8;
+                loop;
+            }
             let ch = self.frame_pos[0];
             self.frame_pos = view(self.frame_pos, 1, self.frame_pos.len());
             self.bit_window |= (ch as i32) << (16 - self.bits_in_window);
@@ -347,6 +448,325 @@ impl Bitstream {
         }
         return result;
     }
+
+    // Returns the number of bits read so far from the frame that this bitstream started at.
+    fn bit_position(frame: &[u8]) -> uint {
+        (frame.len() + self.bytes_past_end - self.frame_pos.len()) * 8 -
+            (self.bits_in_window as uint)
+    }
+}
+
+// Frame headers
+
+struct FrameHeader {
+    protected: bool;            // A CRC follows the header.
+    bit_rate_index: i32;        // 1-14
+    sampling_frequency: i32;    // 0-2
+    padding_bit: i32;
+    private_bit: i32;
+    mode: Mode;
+    mode_extension: i32;
+    copyright: i32;
+    original: i32;
+    emphasis: i32;
+}
+
+impl FrameHeader {
+    fn bit_rate() -> i32 {
+        BITRATES[self.bit_rate_index - 1]
+    }
+
+    fn sample_rate() -> i32 {
+        SAMPLE_RATES[self.sampling_frequency]
+    }
+
+    fn num_channels() -> i32 {
+        if self.mode == Mono { 1 } else { 2 }
+    }
+
+    fn frame_size() -> uint {
+        (144000 * self.bit_rate() / self.sample_rate() + self.padding_bit) as uint
+    }
+}
+
+// Parses the 4-byte header at the start of the given frame without decoding anything else.
+fn parse_header(frame: &[u8]) -> MP2Result<FrameHeader> {
+    if frame.len() < 4 {
+        return Error("truncated header");
+    }
+    if frame[0] != 0xff || (frame[1] & 0xfe) != 0xfc {
+        return Error("invalid MPEG-Audio Layer II header");
+    }
+    let bit_rate_index = (frame[2] >> 4) as i32;
+    if bit_rate_index == 0 || bit_rate_index == 15 {
+        return Error("invalid bit rate or 'free format'");
+    }
+    let sampling_frequency = ((frame[2] >> 2) & 3) as i32;
+    if sampling_frequency == 3 {
+        return Error("invalid sampling frequency");
+    }
+    return OK(FrameHeader {
+        protected: (frame[1] & 1) == 0,
+        bit_rate_index: bit_rate_index,
+        sampling_frequency: sampling_frequency,
+        padding_bit: ((frame[2] >> 1) & 1) as i32,
+        private_bit: (frame[2] & 1) as i32,
+        mode: Mode((frame[3] >> 6) as i32),
+        mode_extension: ((frame[3] >> 4) & 3) as i32,
+        copyright: ((frame[3] >> 3) & 1) as i32,
+        original: ((frame[3] >> 2) & 1) as i32,
+        emphasis: (frame[3] & 3) as i32
+    });
+}
+
+// Finds the first frame boundary in the given data. To avoid being fooled by stray syncwords, a
+// candidate must be followed by another valid header with the same sample rate, unless the data
+// ends first.
+fn find_sync(data: &[u8]) -> option<uint> {
+    let mut pos = 0;
+    while pos + 4 <= data.len() {
+        if data[pos] == 0xff {
+            match parse_header(view(data, pos, data.len())) {
+                OK(header) => {
+                    let next = pos + header.frame_size();
+                    if next + 4 > data.len() {
+                        return Some(pos);
+                    }
+                    match parse_header(view(data, next, data.len())) {
+                        OK(next_header) if next_header.sampling_frequency ==
+                                header.sampling_frequency => return Some(pos),
+                        _ => {}
+                    }
+                }
+                Error(_) => {}
+            }
+        }
+        pos += 1;
+    }
+    return None;
+}
+
+// Side information
+//
+// The side information of a frame is everything before the samples: allocations, scale factor
+// selectors and scale factors. Tools that edit or check frames without decoding them read it
+// here, along with where each part sits in the frame.
+
+struct SideInfo {
+    header: FrameHeader;
+    sblimit: uint;
+    bound: uint;
+    num_channels: uint;
+
+    // Allocation codes as transmitted, and the quantizer each selects (an index into
+    // QUANTIZER_TABLE plus one, or 0 for none); copied to channel 1 above the bound
+    allocation_code: [[mut u8]/32]/2;
+    quantizer: [[mut u8]/32]/2;
+
+    scfsi: [[mut u8]/32]/2;
+    scalefactor: [[[mut u8]/3]/32]/2;
+
+    // Bit position of each subband's first transmitted scale factor, the end of the part of
+    // the frame covered by the CRC and the start of the samples
+    scalefactor_bit: [[mut uint]/32]/2;
+    crc_end_bit: uint;
+    samples_bit: uint;
+}
+
+// Reads the scale factors of the three parts of a frame for one subband, as the scale factor
+// selector says.
+fn read_scalefactors(bitstream: Bitstream, scfsi: i32, scalefactor: &[mut i32]) {
+    match scfsi {
+        0 => {
+            scalefactor[0] = bitstream.get_bits(6);
+            scalefactor[1] = bitstream.get_bits(6);
+            scalefactor[2] = bitstream.get_bits(6);
+        }
+        1 => {
+            let a = bitstream.get_bits(6);
+            scalefactor[0] = a;
+            scalefactor[1] = a;
+            scalefactor[2] = bitstream.get_bits(6);
+        }
+        2 => {
+            let a = bitstream.get_bits(6);
+            scalefactor[0] = a;
+            scalefactor[1] = a;
+            scalefactor[2] = a;
+        }
+        3 => {
+            scalefactor[0] = bitstream.get_bits(6);
+            let a = bitstream.get_bits(6);
+            scalefactor[1] = a;
+            scalefactor[2] = a;
+        }
+        _ => fail
+    }
+}
+
+// Reads the side information of a frame, which must be complete.
+fn read_side_info(context: &MP2Context, frame: &[u8]) -> MP2Result<SideInfo> {
+    let header = match parse_header(frame) {
+        OK(header) => header,
+        Error(e) => return Error(e)
+    };
+    if header.frame_size() > frame.len() {
+        return Error("truncated frame");
+    }
+
+    // Skip the rest of the header, and the CRC if present.
+    let bitstream = Bitstream {
+        bit_window: (frame[2] as i32) << 16,
+        bits_in_window: 8,
+        frame_pos: view(frame, 3, frame.len()),
+        bytes_past_end: 0
+    };
+    ignore(bitstream.get_bits(8));
+    ignore(bitstream.get_bits(8));
+    if header.protected {
+        ignore(bitstream.get_bits(16));
+    }
+
+    let num_channels = if header.mode == Mono { 1 } else { 2 };
+    let table_idx = if header.mode == Mono { 0 } else { 1 };
+    let table_idx = context.constants.QUANT_LUT_STEP1[table_idx][header.bit_rate_index - 1] as i32;
+    let table_idx = context.constants.QUANT_LUT_STEP2[table_idx][header.sampling_frequency] as i32;
+    let sblimit = (table_idx & 63) as uint;
+    let table_idx = table_idx >> 6;
+    let bound = match header.mode {
+        JointStereo => uint::min(((header.mode_extension + 1) << 2) as uint, sblimit),
+        Mono => 0,
+        _ => sblimit
+    };
+
+    let info = SideInfo {
+        header: header,
+        sblimit: sblimit,
+        bound: bound,
+        num_channels: num_channels,
+        allocation_code: [ [ mut 0, ..32 ], [ mut 0, ..32 ] ],
+        quantizer: [ [ mut 0, ..32 ], [ mut 0, ..32 ] ],
+        scfsi: [ [ mut 0, ..32 ], [ mut 0, ..32 ] ],
+        scalefactor: [ [ [ mut 0, 0, 0 ], ..32 ], [ [ mut 0, 0, 0 ], ..32 ] ],
+        scalefactor_bit: [ [ mut 0, ..32 ], [ mut 0, ..32 ] ],
+        crc_end_bit: 0,
+        samples_bit: 0
+    };
+
+    // Allocations, shared by both channels above the bound
+    for uint::range(0, sblimit) |sb| {
+        let row = context.constants.QUANT_LUT_STEP3[table_idx][sb] as i32;
+        for uint::range(0, num_channels) |ch| {
+            if ch == 0 || sb < bound {
+                let code = bitstream.get_bits(row >> 4);
+                info.allocation_code[ch][sb] = code as u8;
+                info.quantizer[ch][sb] = context.constants.QUANT_LUT_STEP4[row & 15][code] as u8;
+            }
+        }
+        if sb >= bound {
+            info.allocation_code[1][sb] = info.allocation_code[0][sb];
+            info.quantizer[1][sb] = info.quantizer[0][sb];
+        }
+    }
+
+    // Scale factor selectors, then scale factors
+    for uint::range(0, sblimit) |sb| {
+        for uint::range(0, num_channels) |ch| {
+            if info.quantizer[ch][sb] != 0 {
+                info.scfsi[ch][sb] = bitstream.get_bits(2) as u8;
+            }
+        }
+    }
+    info.crc_end_bit = bitstream.bit_position(frame);
+    for uint::range(0, sblimit) |sb| {
+        for uint::range(0, num_channels) |ch| {
+            if info.quantizer[ch][sb] != 0 {
+                info.scalefactor_bit[ch][sb] = bitstream.bit_position(frame);
+                let scalefactor = [ mut 0, 0, 0 ];
+                read_scalefactors(bitstream, info.scfsi[ch][sb] as i32, scalefactor);
+                for uint::range(0, 3) |part| {
+                    info.scalefactor[ch][sb][part] = scalefactor[part] as u8;
+                }
+            }
+        }
+    }
+    info.samples_bit = bitstream.bit_position(frame);
+    if info.samples_bit > header.frame_size() * 8 {
+        return Error("side information overruns the frame");
+    }
+    return OK(info);
+}
+
+// Computes the CRC-16 of a frame's header and side information (x^16 + x^15 + x^2 + 1, starting
+// from all ones), as stored after the header of protected frames.
+fn frame_crc(frame: &[u8], crc_end_bit: uint) -> uint {
+    let mut crc = 0xffff;
+    let mut add_bit = |bit: uint| {
+        crc = if ((crc >> 15) ^ bit) != 0 { ((crc << 1) ^ 0x8005) & 0xffff } else {
+            (crc << 1) & 0xffff
+        };
+    };
+    for uint::range(16, 32) |pos| {
+        add_bit(((frame[pos / 8] >> (7 - pos % 8)) & 1) as uint);
+    }
+    for uint::range(48, crc_end_bit) |pos| {
+        add_bit(((frame[pos / 8] >> (7 - pos % 8)) & 1) as uint);
+    }
+    return crc;
+}
+
+// Computes the DAB ScF-CRC words covering a frame's scale factors; see MP2Stream::decode_frame().
+// The first function gives the scale factor selector of a channel's subband, or None if it has
+// no allocation, and the second gives a scale factor by channel, subband and part. As in
+// EN 300 401, only the 3 most significant bits of each scale factor are covered.
+fn scf_crc_words(sblimit: uint, num_channels: uint, scfsi: fn(uint, uint) -> option<uint>,
+                 scalefactor: fn(uint, uint, uint) -> uint, words: &[mut u8]) {
+    let count = words.len();
+    let bounds = if count == 4 { [ 0u, 4, 8, 16, 32 ] } else { [ 0u, 4, 32, 32, 32 ] };
+    for uint::range(0, count) |group| {
+        let mut crc = 0xff;
+        for uint::range(bounds[group], uint::min(bounds[group + 1], sblimit)) |sb| {
+            for uint::range(0, num_channels) |ch| {
+                match scfsi(ch, sb) {
+                    Some(scfsi) => {
+                        for SCF_TRANSMITTED[scfsi].each |part| {
+                            if *part >= 0 {
+                                crc = crc8_update(crc, scalefactor(ch, sb, *part as uint) >> 3, 3);
+                            }
+                        }
+                    }
+                    None => {}
+                }
+            }
+        }
+        words[group] = crc as u8;
+    }
+}
+
+impl SideInfo {
+    // Computes the DAB ScF-CRC words covering the frame's scale factors.
+    fn scf_crc_words(words: &[mut u8]) {
+        scf_crc_words(self.sblimit, self.num_channels,
+                      |ch, sb| if self.quantizer[ch][sb] != 0 {
+                          Some(self.scfsi[ch][sb] as uint)
+                      } else {
+                          None
+                      },
+                      |ch, sb, part| self.scalefactor[ch][sb][part] as uint, words);
+    }
+}
+
+// Writes the low bits of a value at the given bit position.
+fn put_bits(data: &[mut u8], bit: uint, bit_count: uint, value: uint) {
+    for uint::range(0, bit_count) |i| {
+        let pos = bit + i;
+        let mask = 0x80u8 >> (pos % 8);
+        if ((value >> (bit_count - 1 - i)) & 1) != 0 {
+            data[pos / 8] |= mask;
+        } else {
+            data[pos / 8] &= !mask;
+        }
+    }
 }
 
 // Frame decoding
@@ -413,9 +833,209 @@ impl MP2Stream {
         }
     }
 
+    // Runs one granule of subband samples (3 per subband and channel) through the synthesis
+    // filterbank, writing 96 stereo samples to the PCM buffer.
+    fn synthesize(sample: &[[[mut i32]/3]/32]/2, pcm: &[mut i16]) {
+        for range(0, 3) |idx| {
+            // Shifting step
+            let table_idx = (self.Voffs - 64) & 1023;
+            self.Voffs = table_idx;
+
+            for range(0, 2) |ch| {
+                self.synthesize_channel(self.V[ch], table_idx, &sample[ch], idx,
+                                        mut_view(pcm, ((idx << 6) | ch) as uint, pcm.len()), 2);
+            }
+        }   // End of synthesis sub-block loop.
+    }
+
+    // Runs one subband sample of each subband (sample[sb][idx]) of one channel through the
+    // synthesis filterbank whose V buffer is given, writing 32 samples to every stride-th element
+    // of the PCM buffer. The caller does the shifting step.
+    fn synthesize_channel(V: &[mut i32], table_idx: i32, sample: &[[mut i32]/3]/32, idx: i32,
+                          pcm: &[mut i16], stride: uint) {
+        // Matrixing
+        for range(0, 64) |i| {
+            let mut sum = 0;
+            for range(0, 32) |j| {
+                sum += self.context.N[i][j] * sample[j][idx];   // 8b * 15b = 23b
+            }
+            // Intermediate value is 28-bit (23 + 5), clamp to 14 bit.
+            V[table_idx + i] = (sum + 8192) >> 14;
+        }
+
+        // Construction of U
+        for range(0, 8) |i| {
+            for range(0, 32) |j| {
+                self.U[(i<<6)+j]    = V[(table_idx+(i<<7)+j)    & 1023];
+                self.U[(i<<6)+j+32] = V[(table_idx+(i<<7)+j+96) & 1023];
+            }
+        }
+
+        // Apply window.
+        for range(0, 512) |i| {
+            self.U[i] = (self.U[i] * D[i] + 32) >> 6;
+        }
+
+        // Output samples.
+        for range(0, 32) |j| {
+            let mut sum: i32 = 0;
+            for range(0, 16) |i| {
+                sum -= self.U[(i << 5) + j];
+            }
+            sum = (sum + 8) >> 4;
+            if self.gain != 65536 {
+                sum = (((sum as i64) * self.gain + 32768) >> 16) as i32;
+            }
+            if sum < -32768 {
+                sum = -32768;
+            }
+            if sum > 32767 {
+                sum = 32767;
+            }
+            pcm[(j as uint) * stride] = sum as i16;
+        }
+    }
+
+    // Pushes the samples still held in the synthesis filterbank out by synthesizing a frame of
+    // silence. The first DECODER_DELAY samples written are the tail of the stream.
+    fn flush(pcm: &[mut i16]) {
+        let output = pcm;
+        let mut pcm = pcm;
+        let sample = [ [ [ mut 0, 0, 0 ], ..32 ], [ [ mut 0, 0, 0 ], ..32 ] ];
+        for 12.times {
+            self.synthesize(&sample, pcm);
+            pcm = mut_view(pcm, 192, pcm.len());
+        }
+        self.deemphasize_output(mut_view(output, 0, SAMPLES_PER_FRAME * 2));
+    }
+
+    // De-emphasis
+
+    // Sets up the de-emphasis filter for the given emphasis field and sample rate, clearing its
+    // state if either changed. The filter is the bilinear transform of the analog de-emphasis
+    // H(s) = (1 + s t2) / (1 + s t1): t1 = 50 us and t2 = 15 us, or for J.17 a pole at 3000 rad/s
+    // and a zero 18.75 dB higher.
+    fn set_emphasis(emphasis: i32, sample_rate: i32) {
+        if emphasis == self.deemph_emphasis && sample_rate == self.deemph_sample_rate {
+            return;
+        }
+        self.deemph_emphasis = emphasis;
+        self.deemph_sample_rate = sample_rate;
+        for range(0, 12) |i| {
+            self.deemph_state[i] = 0;
+        }
+
+        let (t1, t2) = match emphasis {
+            EMPHASIS_50_15 => (50.0e-6, 15.0e-6),
+            EMPHASIS_J17 => (1.0 / 3000.0, 1.0 / 25980.76),    // 3000 * sqrt(75)
+            _ => return
+        };
+        let k = 2.0 * (sample_rate as float);
+        let scale = 1073741824.0 / (1.0 + t1 * k);                 // 2^30
+        self.deemph_coeffs[0] = ((1.0 + t2 * k) * scale) as i64;
+        self.deemph_coeffs[1] = ((1.0 - t2 * k) * scale) as i64;
+        self.deemph_coeffs[2] = ((1.0 - t1 * k) * scale) as i64;
+    }
+
+    // Applies the de-emphasis filter to the stereo output of a frame, unless the caller does.
+    fn deemphasize_output(pcm: &[mut i16]) {
+        if !self.deemph_by_caller {
+            self.deemphasize(pcm, 2);
+        }
+    }
+
+    // Applies the de-emphasis filter to interleaved output with the given number of channels.
+    fn deemphasize(pcm: &[mut i16], channels: uint) {
+        if !self.deemphasis ||
+                (self.deemph_emphasis != EMPHASIS_50_15 && self.deemph_emphasis != EMPHASIS_J17) {
+            return;
+        }
+        let b0 = self.deemph_coeffs[0];
+        let b1 = self.deemph_coeffs[1];
+        let a1 = self.deemph_coeffs[2];
+        for uint::range(0, pcm.len() / channels) |i| {
+            for uint::range(0, channels) |ch| {
+                let x = pcm[i * channels + ch] as i32;
+                let x1 = self.deemph_state[ch * 2] as i64;
+                let y1 = self.deemph_state[ch * 2 + 1] as i64;
+                let mut y = ((b0 * (x as i64) + b1 * x1 - a1 * y1 + (1 << 29)) >> 30) as i32;
+                if y < -32768 {
+                    y = -32768;
+                }
+                if y > 32767 {
+                    y = 32767;
+                }
+                self.deemph_state[ch * 2] = x;
+                self.deemph_state[ch * 2 + 1] = y;
+                pcm[i * channels + ch] = y as i16;
+            }
+        }
+    }
+
+    // Error concealment
+
+    // Outputs a frame's worth of samples in place of a frame that couldn't be decoded. The
+    // samples go through the synthesis filterbank like any others, so there's no click when
+    // decoding resumes, and the output stays SAMPLES_PER_FRAME samples per frame.
+    fn conceal_frame(pcm: &[mut i16]) {
+        let output = pcm;
+        let mut pcm = pcm;
+
+        // Gains at the start and end of this frame, in 1/256ths.
+        let fade_frames = match self.conceal_mode {
+            ConcealRepeat => CONCEAL_FADE_FRAMES,
+            ConcealMute => 1,
+            ConcealSilence => 0
+        };
+        let n = self.concealed_frames;
+        let (start_gain, end_gain) = if n < fade_frames {
+            ((256 * (fade_frames - n) / fade_frames) as i32,
+             (256 * (fade_frames - n - 1) / fade_frames) as i32)
+        } else {
+            (0, 0)
+        };
+        self.concealed_frames += 1;
+
+        // The scale factors carried over from before the error no longer apply.
+        self.scf_crc_count = 0;
+
+        let sample = [ [ [ mut 0, 0, 0 ], ..32 ], [ [ mut 0, 0, 0 ], ..32 ] ];
+        for range(0, 12) |granule| {
+            let gain = start_gain + (end_gain - start_gain) * granule / 12;
+            for range(0, 2) |ch| {
+                for range(0, 32) |sb| {
+                    for range(0, 3) |idx| {
+                        sample[ch][sb][idx] = (self.prev_sample[granule][ch][sb][idx] * gain) >> 8;
+                    }
+                }
+            }
+            self.synthesize(&sample, pcm);
+            pcm = mut_view(pcm, 192, pcm.len());
+        }
+        self.deemphasize_output(mut_view(output, 0, SAMPLES_PER_FRAME * 2));
+    }
+
+    // Sets the gain applied to the output, in dB.
+    fn set_gain(db: float) {
+        self.gain = (65536.0 * float::pow(10.0, db / 20.0)) as i64;
+    }
+
     // Main functions
 
+    // Returns the new format if the last frame decoded changed it, once per change. The first
+    // frame always counts as a change.
+    fn format_change() -> option<AudioFormat> {
+        if !self.format_changed {
+            return None;
+        }
+        self.format_changed = false;
+        return Some(copy self.format);
+    }
+
     fn get_sample_rate(frame: &[u8]) -> MP2Result<i32> {
+        if frame.len() < 3 {
+            return Error("truncated header");
+        }
         if frame[0] != 0xff {
             return Error("no valid syncword");
         }
@@ -432,6 +1052,9 @@ impl MP2Stream {
         let mut pcm = pcm;
 
         // Check for valid header; syncword OK, MPEG-Audio Layer II
+        if frame.len() < 4 {
+            return Error("truncated header");
+        }
         if frame[0] != 0xff || (frame[1] & 0xfe) != 0xfc {
             return Error("invalid MPEG-Audio Layer II header");
         }
@@ -440,12 +1063,13 @@ impl MP2Stream {
         let bitstream = Bitstream {
             bit_window: (frame[2] as i32) << 16,
             bits_in_window: 8,
-            frame_pos: view(frame, 3, frame.len())
+            frame_pos: view(frame, 3, frame.len()),
+            bytes_past_end: 0
         };
 
         // Read the rest of the header.
         let bit_rate_index_minus1 = bitstream.get_bits(4) - 1;
-        if bit_rate_index_minus1 > 13 {
+        if bit_rate_index_minus1 < 0 || bit_rate_index_minus1 > 13 {
             return Error("invalid bit rate or 'free format'");
         }
         let sampling_frequency = bitstream.get_bits(2);
@@ -472,8 +1096,10 @@ impl MP2Stream {
             }
         }
 
-        // Discard the last 4 bits of the header and the CRC value if present.
-        ignore(bitstream.get_bits(4));
+        // Discard the copyright and original bits, read the emphasis and discard the CRC value if
+        // present.
+        ignore(bitstream.get_bits(2));
+        let emphasis = bitstream.get_bits(2);
         if (frame[1] & 1) == 0 {
             ignore(bitstream.get_bits(16));
         }
@@ -482,9 +1108,23 @@ impl MP2Stream {
         let mut frame_size = 144000 * BITRATES[bit_rate_index_minus1];
         frame_size /= SAMPLE_RATES[sampling_frequency];
         frame_size += padding_bit;
-        if pcm.len() < (frame_size as uint) {
+        if (frame_size as uint) > frame.len() {
+            return Error("truncated frame");
+        }
+        if pcm.len() < SAMPLES_PER_FRAME * 2 {
             return Error("PCM too small");
         }
+        let output = pcm;
+        self.set_emphasis(emphasis, SAMPLE_RATES[sampling_frequency]);
+
+        // Note changes of sample rate or channel count, as at programme boundaries.
+        let channels: uint = if mode == Mono { 1 } else { 2 };
+        if SAMPLE_RATES[sampling_frequency] != self.format.sample_rate ||
+                channels != self.format.channels {
+            self.format = AudioFormat { sample_rate: SAMPLE_RATES[sampling_frequency],
+                                        channels: channels };
+            self.format_changed = true;
+        }
 
         // Prepare the quantizer table lookups.
         let mut table_idx = if mode == Mono { 0 } else { 1 };
@@ -530,32 +1170,7 @@ impl MP2Stream {
         for range(0, sblimit) |sb| {
             for range(0, num_channels) |ch| {
                 if allocation[ch][sb].is_some() {
-                    match scfsi[ch][sb] {
-                        0 => {
-                            scalefactor[ch][sb][0] = bitstream.get_bits(6);
-                            scalefactor[ch][sb][1] = bitstream.get_bits(6);
-                            scalefactor[ch][sb][2] = bitstream.get_bits(6);
-                        }
-                        1 => {
-                            let a = bitstream.get_bits(6);
-                            scalefactor[ch][sb][0] = a;
-                            scalefactor[ch][sb][1] = a;
-                            scalefactor[ch][sb][2] = bitstream.get_bits(6);
-                        }
-                        2 => {
-                            let a = bitstream.get_bits(6);
-                            scalefactor[ch][sb][0] = a;
-                            scalefactor[ch][sb][1] = a;
-                            scalefactor[ch][sb][2] = a;
-                        }
-                        3 => {
-                            scalefactor[ch][sb][0] = bitstream.get_bits(6);
-                            let a = bitstream.get_bits(6);
-                            scalefactor[ch][sb][1] = a;
-                            scalefactor[ch][sb][2] = a;
-                        }
-                        _ => fail
-                    }
+                    read_scalefactors(bitstream, scfsi[ch][sb], scalefactor[ch][sb]);
                 }
             }
             if mode == Mono {
@@ -565,10 +1180,62 @@ impl MP2Stream {
             }
         }
 
+        // Check the scale factors against the DAB ScF-CRC carried by the previous frame. Each
+        // check word covers a group of subbands: 0-3, 4-7, 8-15 and 16 up, or 0-3 and 4 up when
+        // there are only two.
+        if self.scf_crc_mode != ScfCrcOff && self.scf_crc_count > 0 {
+            let bounds = if self.scf_crc_count == 4 {
+                [ 0, 4, 8, 16, 32 ]
+            } else {
+                [ 0, 4, 32, 32, 32 ]
+            };
+            let words = [ mut 0u8, 0, 0, 0 ];
+            scf_crc_words(sblimit as uint, num_channels as uint,
+                          |ch, sb| if allocation[ch][sb].is_some() {
+                              Some(scfsi[ch][sb] as uint)
+                          } else {
+                              None
+                          },
+                          |ch, sb, part| scalefactor[ch][sb][part] as uint,
+                          mut_view(words, 0, self.scf_crc_count));
+            for range(0, self.scf_crc_count as i32) |group| {
+                let end = if bounds[group + 1] < sblimit { bounds[group + 1] } else { sblimit };
+                if words[group] != self.scf_crc[group] {
+                    // Conceal the error; wrong scale factors cause loud artifacts.
+                    for range(bounds[group], end) |sb| {
+                        for range(0, 2) |ch| {
+                            for range(0, 3) |part| {
+                                scalefactor[ch][sb][part] = match self.scf_crc_mode {
+                                    ScfCrcReuse => self.prev_scalefactor[ch][sb][part],
+                                    // Scale factor 63 is silence; leave it silent.
+                                    _ if scalefactor[ch][sb][part] == 63 => 63,
+                                    _ => {
+                                        let scf = scalefactor[ch][sb][part] + SCF_CRC_ATTENUATION;
+                                        if scf > 62 { 62 } else { scf }
+                                    }
+                                };
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        for range(0, 2) |ch| {
+            for range(0, 32) |sb| {
+                for range(0, 3) |part| {
+                    self.prev_scalefactor[ch][sb][part] = if allocation[ch][sb].is_some() {
+                        scalefactor[ch][sb][part]
+                    } else {
+                        63
+                    };
+                }
+            }
+        }
+
         // Perform coefficient input and reconstruction.
         let sample = [ [ [ mut 0, 0, 0 ], ..32 ], [ [ mut 0, 0, 0 ], ..32 ] ];
-        for range(0, 3) |part| {    // For each part...
-            for 4.times {           // For each granule...
+        for range(0, 3) |part| {        // For each part...
+            for range(0, 4) |gr| {      // For each granule...
                 // Read the samples.
                 for range(0, bound) |sb| {
                     for range(0, 2) |ch| {
@@ -591,101 +1258,5949 @@ impl MP2Stream {
                     }
                 }
 
-                // Synthesis loop
-                for range(0, 3) |idx| {
-                    // Shifting step
-                    table_idx = (self.Voffs - 64) & 1023;
-                    self.Voffs = table_idx;
-
-                    for range(0, 2) |ch| {
-                        // Matrixing
-                        for range(0, 64) |i| {
-                            let mut sum = 0;
-                            for range(0, 32) |j| {
-                                sum += self.context.N[i][j] * sample[ch][j][idx]; // 8b * 15b = 23b
-                            }
-                            // Intermediate value is 28-bit (23 + 5), clamp to 14 bit.
-                            self.V[ch][table_idx + i] = (sum + 8192) >> 14;
-                        }
-
-                        // Construction of U
-                        for range(0, 8) |i| {
-                            for range(0, 32) |j| {
-                                self.U[(i<<6)+j]    = self.V[ch][(table_idx+(i<<7)+j)    & 1023];
-                                self.U[(i<<6)+j+32] = self.V[ch][(table_idx+(i<<7)+j+96) & 1023];
-                            }
-                        }
-
-                        // Apply window.
-                        for range(0, 512) |i| {
-                            self.U[i] = (self.U[i] * D[i] + 32) >> 6;
+                // Keep the samples around for concealing errors in the next frame.
+                let saved = &self.prev_sample[(part << 2) + gr];
+                for range(0, 2) |ch| {
+                    for range(0, 32) |sb| {
+                        for range(0, 3) |idx| {
+                            saved[ch][sb][idx] = sample[ch][sb][idx];
                         }
+                    }
+                }
 
-                        // Output samples.
-                        for range(0, 32) |j| {
-                            let mut sum: i32 = 0;
-                            for range(0, 16) |i| {
-                                sum -= self.U[(i << 5) + j];
-                            }
-                            sum = (sum + 8) >> 4;
-                            if sum < -32768 {
-                                sum = -32768;
-                            }
-                            if sum > 32767 {
-                                sum = 32767;
-                            }
-                            pcm[(idx << 6) | (j << 1) | ch] = sum as i16;
-                        }
-                    }   // End of synthesis channel loop.
-                }   // End of synthesis sub-block loop.
+                self.synthesize(&sample, pcm);
 
                 // Adjust PCM output slice: decoded 3 * 32 = 96 stereo samples.
                 pcm = mut_view(pcm, 192, pcm.len());
             }
         }
 
+        self.deemphasize_output(mut_view(output, 0, SAMPLES_PER_FRAME * 2));
+        self.concealed_frames = 0;
+
+        // Remember where the audio data ended so the ancillary data can be found.
+        self.frame_size = frame_size as uint;
+        self.audio_end_bit = bitstream.bit_position(frame);
+
+        // Pick up the ScF-CRC for the next frame. It sits just before the F-PAD, stored
+        // backwards like the X-PAD.
+        if self.scf_crc_mode != ScfCrcOff {
+            let bit_rate_per_channel = BITRATES[bit_rate_index_minus1] / num_channels;
+            let count: uint = if bit_rate_per_channel >= 56 { 4 } else { 2 };
+            if self.audio_end_bit + (count + 2) * 8 <= self.frame_size * 8 {
+                for uint::range(0, count) |i| {
+                    self.scf_crc[i] = frame[self.frame_size - 3 - i];
+                }
+                self.scf_crc_count = count;
+            } else {
+                self.scf_crc_count = 0;
+            }
+        }
+
         return OK(frame_size);
     }
-}
 
-// Entry point
+    // Ancillary data
 
-fn main(args: ~[UniqueString]) {
-    if args.len() < 2 {
-        println(fmt!("usage: %s file.mp2", args[0]));
-        return;
+    // Returns the ancillary data of the last frame decoded, which must be passed in again. The
+    // ancillary data starts at audio_end_bit, which needn't be byte-aligned; this returns the
+    // whole bytes after it. Use copy_ancillary_data() for the exact bits.
+    fn ancillary_data(frame: &a/[u8]) -> &a/[u8] {
+        let start = (self.audio_end_bit + 7) / 8;
+        if start >= self.frame_size || self.frame_size > frame.len() {
+            return view(frame, 0, 0);
+        }
+        return view(frame, start, self.frame_size);
     }
 
-    let result = io::read_whole_file(args[1]);
-    let bytes = match result {
-        OK(_)    => unwrap(result),
-        Error(e) => { println(e); return; }
-    };
-    let mut bytes = view(bytes, 0, bytes.len());
+    // Copies the ancillary data bits of the last frame decoded into the given buffer, starting at
+    // the most significant bit of its first byte. Returns the number of bits copied.
+    fn copy_ancillary_data(frame: &[u8], out: &[mut u8]) -> uint {
+        if self.frame_size > frame.len() || self.audio_end_bit >= self.frame_size * 8 {
+            return 0;
+        }
+        let mut bit_count = self.frame_size * 8 - self.audio_end_bit;
+        if bit_count > out.len() * 8 {
+            bit_count = out.len() * 8;
+        }
 
-    let context = MP2Context();
-    let stream = MP2Stream(&context);
-    let sample_rate = stream.get_sample_rate(bytes).get() as int;
-    println(fmt!("sample rate is %d", sample_rate));
+        let shift = self.audio_end_bit & 7;
+        let start = self.audio_end_bit / 8;
+        for uint::range(0, (bit_count + 7) / 8) |i| {
+            let mut byte = (frame[start + i] as uint) << shift;
+            if shift != 0 && start + i + 1 < self.frame_size {
+                byte |= (frame[start + i + 1] as uint) >> (8 - shift);
+            }
+            out[i] = byte as u8;
+        }
+        return bit_count;
+    }
+}
 
-    let ao = ao::AO();
-    let sample_format = ao::SampleFormat(16, sample_rate as i32, 2, ao::Little);
-    let device = ao.open_live(ao.default_driver_id(), &sample_format);
+// Multichannel extension
+//
+// ISO 13818-3 hides up to three more channels and a low-frequency enhancement (LFE) channel in
+// the ancillary data of MPEG-1 Layer II stereo frames, so that MPEG-1 decoders play a stereo
+// downmix (Lo, Ro). The multichannel (MC) data follows the MPEG-1 audio data: a header giving the
+// channel configuration, a CRC, the composite coding tools in use, then allocation, scale factors
+// and samples much as in MPEG-1. The extra channels are synthesized and subtracted from the
+// downmix ("dematrixing") to give 5.1 output in the order L, R, C, LFE, Ls, Rs.
+//
+// Only part of the standard is supported: configurations up to 3/2 with each channel sent in its
+// own transmission channel (tc_allocation 0) and dematrixing procedures 0, 1 and 3. Frames with
+// an extension bitstream, dynamic crosstalk, prediction, a second stereo programme or procedure 2
+// (which needs a 90 degree phase shift) play as stereo with the other channels silent.
+// Multilingual channels are ignored. The output keeps the level of the downmix, which the encoder
+// scaled down to avoid clipping.
 
-    let pcm = [ mut 0, ..2304 ];    // FIXME: Rust compiler should accept (SAMPLES_PER_FRAME*2).
-    loop {
-        let result = stream.decode_frame(bytes, pcm);
-        let frame_size;
-        match result {
-            OK(size) => frame_size = size as uint,
-            Error(e) => { println(from_slice(e)); return; }
-        }
+const MC_OUTPUT_CHANNELS: uint = 6;
+const MC_CENTER: uint = 0;
+const MC_LFE: uint = 1;
+const MC_LEFT_SURROUND: uint = 2;
+const MC_RIGHT_SURROUND: uint = 3;
 
-        // Write the bytes, in little-endian.
-        device.play(pcm);
+// Centre value for a centre channel limited to the lower 12 subbands
+const MC_CENTER_LIMITED: i32 = 3;
 
-        if bytes.len() < frame_size { return; }
-        bytes = view(bytes, frame_size, bytes.len());
+// Dematrixing weights of the centre and surround channels, in 1/32768, by dematrix procedure
+const DEMATRIX_CENTER: [i32]/4 = [ 23170, 23170, 0, 0 ];
+const DEMATRIX_SURROUND: [i32]/4 = [ 23170, 16384, 0, 0 ];
+
+struct McDecoder {
+    // Synthesis filterbank state for the centre, LFE and surround channels
+    V: [[mut i32]/1024]/4;
+    mut Voffs: i32;
+
+    // Granules of the current frame run through the filterbanks so far
+    mut granules: i32;
+
+    // The MC data of the current frame, padded with zeros so that reading past the end of
+    // corrupt data is harmless
+    data: [mut u8]/8192;
+
+    // Dematrixing weights for the last frame, for flushing
+    mut center_weight: i32;
+    mut surround_weight: i32;
+}
+
+fn McDecoder() -> McDecoder {
+    McDecoder {
+        V: [ [ mut 0, ..1024 ], ..4 ],
+        Voffs: 0,
+        granules: 0,
+        data: [ mut 0, ..8192 ],
+        center_weight: 0,
+        surround_weight: 0
     }
 }
 
+impl McDecoder {
+    // Decodes the MC data of the frame the stream has just decoded, whose stereo output is
+    // given, writing 6-channel output. Frames without usable MC data play as stereo, and false
+    // is returned.
+    fn decode(stream: &MP2Stream, frame: &[u8], pcm: &[i16], out: &[mut i16]) -> bool {
+        match self.decode_extension(stream, frame, out) {
+            OK(_) => {
+                self.granules = 0;
+                self.dematrix(pcm, out);
+                self.deemphasize(stream, out);
+                return true;
+            }
+            Error(_) => {
+                self.conceal(stream, pcm, out);
+                return false;
+            }
+        }
+    }
+
+    // Plays a frame without usable MC data, whose stereo output is given, as stereo. The rest of
+    // the frame is synthesized from silence so that the filterbanks stay in step with the stereo
+    // one, and the next frame with MC data doesn't start from stale history.
+    fn conceal(stream: &MP2Stream, pcm: &[i16], out: &[mut i16]) {
+        let sample = [ [ [ mut 0, 0, 0 ], ..32 ], ..4 ];
+        while self.granules < 12 {
+            self.synthesize(stream, &sample, mut_view(out, (self.granules * 576) as uint,
+                                                      out.len()));
+        }
+        self.granules = 0;
+        self.expand(pcm, out);
+        self.deemphasize(stream, out);
+    }
+
+    fn decode_extension(stream: &MP2Stream, frame: &[u8], out: &[mut i16]) -> MP2Result<()> {
+        let header = match parse_header(frame) {
+            OK(header) => header,
+            Error(e) => return Error(e)
+        };
+        if header.mode == Mono || header.mode == DualChannel {
+            return Error("no multichannel downmix");
+        }
+
+        // Copy the data after the MPEG-1 audio data and set up a bitstream reader on it.
+        let start_bit = stream.audio_end_bit;
+        let end = uint::min(stream.frame_size, frame.len());
+        if start_bit / 8 >= end {
+            return Error("no multichannel data");
+        }
+        let size = end - start_bit / 8;
+        for uint::range(0, 8192) |i| {
+            self.data[i] = if i < size { frame[start_bit / 8 + i] } else { 0 };
+        }
+        let available = size * 8 - start_bit % 8;
+        let bitstream = Bitstream {
+            bit_window: (self.data[0] as i32) << 16,
+            bits_in_window: 8,
+            frame_pos: view(self.data, 1, 8192),
+            bytes_past_end: 0
+        };
+        ignore(bitstream.get_bits((start_bit % 8) as i32));
+        let data_start = start_bit % 8;
+
+        // MC header
+        if bitstream.get_bits(1) != 0 {
+            return Error("multichannel extension bitstream");
+        }
+        let center = bitstream.get_bits(2);
+        let surround = bitstream.get_bits(2);
+        let lfe = bitstream.get_bits(1) != 0;
+        ignore(bitstream.get_bits(1));      // Discard the audio mix bit.
+        let dematrix_procedure = bitstream.get_bits(2);
+        ignore(bitstream.get_bits(7));      // Discard the multilingual and copyright fields.
+        ignore(bitstream.get_bits(16));     // Discard the MC CRC.
+        if center == 2 || surround == 3 || dematrix_procedure == 2 {
+            return Error("unsupported multichannel configuration");
+        }
+
+        // Work out which output channel each transmission channel after T0 and T1 carries. A
+        // mono surround channel goes to both surround outputs.
+        let channels = [ mut 0u, 0, 0 ];
+        let mut num_channels = 0;
+        if center != 0 {
+            channels[num_channels] = MC_CENTER;
+            num_channels += 1;
+        }
+        for (surround as uint).times {
+            channels[num_channels] = if num_channels > 0 && channels[num_channels - 1] ==
+                    MC_LEFT_SURROUND {
+                MC_RIGHT_SURROUND
+            } else {
+                MC_LEFT_SURROUND
+            };
+            num_channels += 1;
+        }
+        if num_channels == 0 && !lfe {
+            return Error("no multichannel data");
+        }
+
+        // Composite coding status. Only the default transmission channel allocation is
+        // supported.
+        let tc_sbgr_select = bitstream.get_bits(1);
+        let dyn_cross_on = bitstream.get_bits(1);
+        let mc_prediction_on = bitstream.get_bits(1);
+        let tc_bits = if center != 0 && surround != 0 {
+            3
+        } else if center != 0 || surround != 0 {
+            2
+        } else {
+            0
+        };
+        let tc_count = if tc_sbgr_select == 1 { 1 } else { 12 };
+        for tc_count.times {
+            if bitstream.get_bits(tc_bits) != 0 {
+                return Error("unsupported transmission channel allocation");
+            }
+        }
+        if dyn_cross_on != 0 || mc_prediction_on != 0 {
+            return Error("unsupported multichannel coding tools");
+        }
+
+        // Use the same quantizer tables as the MPEG-1 part.
+        let QUANT_LUT_STEP1 = &stream.context.constants.QUANT_LUT_STEP1;
+        let QUANT_LUT_STEP2 = &stream.context.constants.QUANT_LUT_STEP2;
+        let table_idx = QUANT_LUT_STEP1[1][header.bit_rate_index - 1] as i32;
+        let table_idx = QUANT_LUT_STEP2[table_idx][header.sampling_frequency] as i32;
+        let sblimit = table_idx & 63;
+        let table_idx = table_idx >> 6;
+
+        // Read the allocation information. The LFE channel is coded as in Layer I.
+        let lf_allocation = if lfe { bitstream.get_bits(4) } else { 0 };
+        if lf_allocation == 15 {
+            return Error("invalid LFE allocation");
+        }
+        let allocation = [ [ mut None, ..32 ], [ mut None, ..32 ], [ mut None, ..32 ] ];
+        for range(0, sblimit) |sb| {
+            for range(0, num_channels as i32) |t| {
+                if channels[t] != MC_CENTER || center != MC_CENTER_LIMITED || sb < 12 {
+                    allocation[t][sb] = stream.read_allocation(bitstream, sb, table_idx);
+                }
+            }
+        }
+
+        // Read scale factor selector information and scale factors.
+        let scfsi = [ [ mut 0, ..32 ], [ mut 0, ..32 ], [ mut 0, ..32 ] ];
+        for range(0, sblimit) |sb| {
+            for range(0, num_channels as i32) |t| {
+                if allocation[t][sb].is_some() {
+                    scfsi[t][sb] = bitstream.get_bits(2);
+                }
+            }
+        }
+        let lf_scalefactor = if lf_allocation != 0 { bitstream.get_bits(6) } else { 0 };
+        let scalefactor = [ [ [ mut 0, 0, 0 ], ..32 ], [ [ mut 0, 0, 0 ], ..32 ],
+                            [ [ mut 0, 0, 0 ], ..32 ] ];
+        for range(0, sblimit) |sb| {
+            for range(0, num_channels as i32) |t| {
+                if allocation[t][sb].is_some() {
+                    read_scalefactors(bitstream, scfsi[t][sb], scalefactor[t][sb]);
+                }
+            }
+        }
+        if bitstream.bit_position(self.data) - data_start > available {
+            return Error("truncated multichannel data");
+        }
+
+        // Read the samples and synthesize the extra channels, a granule at a time.
+        let sample = [ [ [ mut 0, 0, 0 ], ..32 ], ..4 ];
+        for range(0, 12) |gr| {
+            let part = gr >> 2;
+            if lf_allocation != 0 {
+                let bits = lf_allocation + 1;
+                let code = bitstream.get_bits(bits);
+                let value = lfe_sample(code, bits, lf_scalefactor);
+
+                // One LFE sample per granule; hold it for the granule.
+                for range(0, 3) |idx| {
+                    sample[MC_LFE][0][idx] = value;
+                }
+            }
+            for range(0, sblimit) |sb| {
+                for range(0, num_channels as i32) |t| {
+                    stream.read_samples(bitstream, allocation[t][sb], scalefactor[t][sb][part],
+                                        sample[channels[t]][sb]);
+                }
+            }
+            if surround == 1 {
+                for range(0, 32) |sb| {
+                    for range(0, 3) |idx| {
+                        sample[MC_RIGHT_SURROUND][sb][idx] = sample[MC_LEFT_SURROUND][sb][idx];
+                    }
+                }
+            }
+            if bitstream.bit_position(self.data) - data_start > available {
+                return Error("truncated multichannel data");
+            }
+
+            self.synthesize(stream, &sample, mut_view(out, (gr * 576) as uint, out.len()));
+        }
+
+        self.center_weight = if center != 0 { DEMATRIX_CENTER[dematrix_procedure] } else { 0 };
+        self.surround_weight = if surround != 0 {
+            DEMATRIX_SURROUND[dematrix_procedure]
+        } else {
+            0
+        };
+        return OK(());
+    }
+
+    // Runs one granule of subband samples for the extra channels through their synthesis
+    // filterbanks, writing 96 samples of each to the 6-channel output.
+    fn synthesize(stream: &MP2Stream, sample: &[[[mut i32]/3]/32]/4, out: &[mut i16]) {
+        self.granules += 1;
+        for range(0, 3) |idx| {
+            let table_idx = (self.Voffs - 64) & 1023;
+            self.Voffs = table_idx;
+            for uint::range(0, 4) |ch| {
+                let offset = (idx as uint) * 32 * MC_OUTPUT_CHANNELS + 2 + ch;
+                stream.synthesize_channel(self.V[ch], table_idx, &sample[ch], idx,
+                                          mut_view(out, offset, out.len()), MC_OUTPUT_CHANNELS);
+            }
+        }
+    }
+
+    // Subtracts the extra channels from the stereo downmix to give the left and right channels.
+    fn dematrix(pcm: &[i16], out: &[mut i16]) {
+        for uint::range(0, SAMPLES_PER_FRAME) |i| {
+            let frame = i * MC_OUTPUT_CHANNELS;
+            let center = (out[frame + 2] as i32) * self.center_weight;
+            for uint::range(0, 2) |ch| {
+                let surround = (out[frame + 4 + ch] as i32) * self.surround_weight;
+                let value = (pcm[i * 2 + ch] as i32) - ((center + surround + 16384) >> 15);
+                out[frame + ch] = if value < -32768 {
+                    -32768
+                } else if value > 32767 {
+                    32767
+                } else {
+                    value as i16
+                };
+            }
+        }
+    }
+
+    // Writes stereo output into the 6-channel output, with the other channels silent.
+    fn expand(pcm: &[i16], out: &[mut i16]) {
+        for uint::range(0, SAMPLES_PER_FRAME) |i| {
+            for uint::range(0, MC_OUTPUT_CHANNELS) |ch| {
+                out[i * MC_OUTPUT_CHANNELS + ch] = if ch < 2 { pcm[i * 2 + ch] } else { 0 };
+            }
+        }
+    }
+
+    // Pushes the samples still held in the synthesis filterbanks out, like MP2Stream::flush(),
+    // whose output is given.
+    fn flush(stream: &MP2Stream, pcm: &[i16], out: &[mut i16]) {
+        let sample = [ [ [ mut 0, 0, 0 ], ..32 ], ..4 ];
+        for range(0, 12) |gr| {
+            self.synthesize(stream, &sample, mut_view(out, (gr * 576) as uint, out.len()));
+        }
+        self.granules = 0;
+        self.dematrix(pcm, out);
+        self.deemphasize(stream, out);
+    }
+
+    // Applies the stream's de-emphasis to the 6-channel output. The extra channels are coded
+    // with the same emphasis as the stereo downmix, so all of them need it.
+    fn deemphasize(stream: &MP2Stream, out: &[mut i16]) {
+        stream.deemphasize(mut_view(out, 0, SAMPLES_PER_FRAME * MC_OUTPUT_CHANNELS),
+                           MC_OUTPUT_CHANNELS);
+    }
+}
+
+// Requantizes an LFE sample, coded as in Layer I with the given number of bits, to the same
+// scale as MP2Stream::read_samples() uses.
+fn lfe_sample(code: i32, bits: i32, scalefactor: i32) -> i32 {
+    let levels = ((1 << bits) - 1) as i64;
+    let val = ((levels - 1 - 2 * (code as i64)) * 32768 / levels) as i32;
+    let scalefactor = SCF_VALUE[scalefactor];
+    return (val * (scalefactor >> 12) + ((val * (scalefactor & 4095) + 2048) >> 12)) >> 12;
+}
+
+// Tags
+//
+// ID3v2 tags may appear at the start of the file; APEv2 and ID3v1 tags may appear at the end (in
+// that order). None of these are MPEG audio, so they must be stripped before decoding.
+
+// Big-endian and little-endian integer readers
+
+fn read_u16_be(data: &[u8], pos: uint) -> uint {
+    ((data[pos] as uint) << 8) | (data[pos + 1] as uint)
+}
+
+fn read_u24_be(data: &[u8], pos: uint) -> uint {
+    ((data[pos] as uint) << 16) | ((data[pos + 1] as uint) << 8) | (data[pos + 2] as uint)
+}
+
+fn read_u32_be(data: &[u8], pos: uint) -> uint {
+    ((data[pos] as uint) << 24) | ((data[pos + 1] as uint) << 16) |
+        ((data[pos + 2] as uint) << 8) | (data[pos + 3] as uint)
+}
+
+fn read_u32_le(data: &[u8], pos: uint) -> uint {
+    ((data[pos + 3] as uint) << 24) | ((data[pos + 2] as uint) << 16) |
+        ((data[pos + 1] as uint) << 8) | (data[pos] as uint)
+}
+
+// ID3v2 sizes are "syncsafe": 7 bits per byte, so that they never contain a syncword.
+fn read_syncsafe(data: &[u8], pos: uint) -> uint {
+    ((data[pos] as uint & 0x7f) << 21) | ((data[pos + 1] as uint & 0x7f) << 14) |
+        ((data[pos + 2] as uint & 0x7f) << 7) | (data[pos + 3] as uint & 0x7f)
+}
+
+fn has_magic(data: &[u8], pos: uint, magic: String) -> bool {
+    if data.len() < pos + magic.len() {
+        return false;
+    }
+    for uint::range(0, magic.len()) |i| {
+        if data[pos + i] != magic[i] {
+            return false;
+        }
+    }
+    return true;
+}
+
+// Text encodings
+const TEXT_LATIN1: u8 = 0;
+const TEXT_UTF16: u8 = 1;      // With byte order mark
+const TEXT_UTF16BE: u8 = 2;
+const TEXT_UTF8: u8 = 3;
+
+// A text field from a tag. The data is a view into the file, so reading tags doesn't allocate.
+struct TagText {
+    encoding: u8;
+    data: &self/[u8];
+}
+
+impl TagText {
+    // Converts the text to a string for display. This allocates, so it's only for output.
+    fn to_str() -> UniqueString {
+        let mut result = ~"";
+        match self.encoding {
+            TEXT_UTF16 | TEXT_UTF16BE => {
+                let mut big_endian = self.encoding == TEXT_UTF16BE;
+                let mut pos = 0;
+                while pos + 1 < self.data.len() {
+                    let unit = if big_endian {
+                        read_u16_be(self.data, pos)
+                    } else {
+                        ((self.data[pos + 1] as uint) << 8) | (self.data[pos] as uint)
+                    };
+                    pos += 2;
+                    match unit {
+                        0xfeff if pos == 2 => {}
+                        0xfffe if pos == 2 => big_endian = !big_endian,
+                        0 => break,
+                        0xd800..0xdfff => str::push_char(&mut result, '?'),
+                        _ => str::push_char(&mut result, unit as char)
+                    }
+                }
+            }
+            TEXT_UTF8 if str::is_utf8(self.data) => {
+                result = str::from_bytes(self.data);
+            }
+            _ => {
+                for self.data.each |b| {
+                    if *b == 0 { break; }
+                    str::push_char(&mut result, *b as char);
+                }
+            }
+        }
+        return str::trim_right(str::trim_right_chars(result, ~['\x00']));
+    }
+}
+
+struct Tags {
+    // The file with all tags stripped.
+    mut audio: &self/[u8];
+
+    mut id3v2_size: uint;
+    mut ape_size: uint;
+    mut id3v1_present: bool;
+
+    mut title: option<TagText/&self>;
+    mut artist: option<TagText/&self>;
+    mut album: option<TagText/&self>;
+    mut comment: option<TagText/&self>;
+
+    // ReplayGain values, as text such as "-6.20 dB" and "0.988525"
+    mut track_gain: option<TagText/&self>;
+    mut track_peak: option<TagText/&self>;
+    mut album_gain: option<TagText/&self>;
+    mut album_peak: option<TagText/&self>;
+}
+
+// Locates and parses the tags in a file.
+fn Tags(bytes: &a/[u8]) -> Tags/&a {
+    let tags = Tags {
+        audio: bytes,
+        id3v2_size: 0,
+        ape_size: 0,
+        id3v1_present: false,
+        title: None,
+        artist: None,
+        album: None,
+        comment: None,
+        track_gain: None,
+        track_peak: None,
+        album_gain: None,
+        album_peak: None
+    };
+
+    let mut start = 0;
+    let mut end = bytes.len();
+
+    // Leading ID3v2 tag. Fields found here take precedence over the others.
+    if has_magic(bytes, 0, "ID3") && bytes.len() >= 10 {
+        let mut size = 10 + read_syncsafe(bytes, 6);
+        if (bytes[5] & 0x10) != 0 {
+            size += 10;     // Footer present.
+        }
+        if size > bytes.len() {
+            size = bytes.len();
+        }
+        tags.id3v2_size = size;
+        tags.read_id3v2(view(bytes, 0, size));
+        start = size;
+    }
+
+    // Trailing ID3v1 tag. It's parsed last so that it has the lowest precedence.
+    if end - start >= 128 && has_magic(bytes, end - 128, "TAG") {
+        tags.id3v1_present = true;
+        end -= 128;
+    }
+
+    // APEv2 tag, which precedes any ID3v1 tag.
+    if end - start >= 32 && has_magic(bytes, end - 32, "APETAGEX") {
+        let footer = view(bytes, end - 32, end);
+        let mut size = read_u32_le(footer, 12);
+        if (read_u32_le(footer, 20) & 0x80000000) != 0 {
+            size += 32;     // Header present.
+        }
+        if size <= end - start && size >= 32 {
+            tags.ape_size = size;
+            tags.read_ape(view(bytes, end - size, end), read_u32_le(footer, 16));
+            end -= size;
+        }
+    }
+
+    if tags.id3v1_present {
+        tags.read_id3v1(view(bytes, bytes.len() - 128, bytes.len()));
+    }
+
+    tags.audio = view(bytes, start, end);
+    return tags;
+}
+
+impl Tags {
+    fn set_if_empty(field: &mut option<TagText/&self>, encoding: u8, data: &self/[u8]) {
+        if field.is_none() && data.len() > 0 {
+            *field = Some(TagText { encoding: encoding, data: data });
+        }
+    }
+
+    fn read_id3v2(tag: &self/[u8]) {
+        let version = tag[3];
+        let flags = tag[5];
+        if version < 2 || version > 4 {
+            return;
+        }
+        if version < 4 && (flags & 0x80) != 0 {
+            // Tag-wide unsynchronization would require copying the frames; just skip the tag.
+            return;
+        }
+
+        let mut pos = 10;
+        if version >= 3 && (flags & 0x40) != 0 && tag.len() >= 14 {
+            // Skip the extended header. Its size excludes itself in v2.3 but not in v2.4.
+            pos += if version == 4 { read_syncsafe(tag, 10) } else { 4 + read_u32_be(tag, 10) };
+        }
+
+        let header_size = if version == 2 { 6 } else { 10 };
+        while pos + header_size <= tag.len() {
+            if tag[pos] == 0 {
+                break;      // Padding.
+            }
+
+            let id_pos = pos;
+            let size = match version {
+                2 => read_u24_be(tag, pos + 3),
+                3 => read_u32_be(tag, pos + 4),
+                _ => read_syncsafe(tag, pos + 4)
+            };
+            pos += header_size;
+            if size == 0 || size > tag.len() - pos {
+                break;
+            }
+
+            let data = view(tag, pos, pos + size);
+            pos += size;
+
+            let encoding = data[0];
+            let text = view(data, 1, data.len());
+            let is = |v22_id: String, id: String| {
+                has_magic(tag, id_pos, if version == 2 { v22_id } else { id })
+            };
+            if is("TT2", "TIT2") {
+                self.set_if_empty(&mut self.title, encoding, text);
+            } else if is("TP1", "TPE1") {
+                self.set_if_empty(&mut self.artist, encoding, text);
+            } else if is("TAL", "TALB") {
+                self.set_if_empty(&mut self.album, encoding, text);
+            } else if is("COM", "COMM") && data.len() >= 4 {
+                // Skip the language code and the content description.
+                let text = skip_terminated_text(encoding, view(data, 4, data.len()));
+                self.set_if_empty(&mut self.comment, encoding, text);
+            } else if is("TXX", "TXXX") && data.len() >= 2 {
+                // User-defined text: a description, then the value.
+                let value = skip_terminated_text(encoding, text);
+                let description = TagText {
+                    encoding: encoding,
+                    data: view(text, 0, text.len() - value.len())
+                };
+                self.read_replaygain(description.to_str(), encoding, value);
+            }
+        }
+    }
+
+    // Picks up a ReplayGain field, given its ID3 TXXX description or APE key.
+    fn read_replaygain(key: UniqueString, encoding: u8, value: &self/[u8]) {
+        match str::to_lower(key) {
+            ~"replaygain_track_gain" => self.set_if_empty(&mut self.track_gain, encoding, value),
+            ~"replaygain_track_peak" => self.set_if_empty(&mut self.track_peak, encoding, value),
+            ~"replaygain_album_gain" => self.set_if_empty(&mut self.album_gain, encoding, value),
+            ~"replaygain_album_peak" => self.set_if_empty(&mut self.album_peak, encoding, value),
+            _ => {}
+        }
+    }
+
+    fn read_ape(tag: &self/[u8], item_count: uint) {
+        // Items start after the header if there is one; the footer is always 32 bytes.
+        let mut pos = if has_magic(tag, 0, "APETAGEX") { 32 } else { 0 };
+        let end = tag.len() - 32;
+        for item_count.times {
+            if pos + 8 > end {
+                break;
+            }
+            let size = read_u32_le(tag, pos);
+            let flags = read_u32_le(tag, pos + 4);
+            pos += 8;
+
+            let key_start = pos;
+            while pos < end && tag[pos] != 0 {
+                pos += 1;
+            }
+            let key = view(tag, key_start, pos);
+            pos += 1;
+            if pos > end || size > end - pos {
+                break;
+            }
+
+            let value = view(tag, pos, pos + size);
+            pos += size;
+
+            if (flags & 6) != 0 || !str::is_utf8(key) {
+                loop;       // Binary or external item, or a corrupt key.
+            }
+            let key = str::from_bytes(key);
+            match str::to_lower(key) {
+                ~"title"   => self.set_if_empty(&mut self.title, TEXT_UTF8, value),
+                ~"artist"  => self.set_if_empty(&mut self.artist, TEXT_UTF8, value),
+                ~"album"   => self.set_if_empty(&mut self.album, TEXT_UTF8, value),
+                ~"comment" => self.set_if_empty(&mut self.comment, TEXT_UTF8, value),
+                _ => self.read_replaygain(key, TEXT_UTF8, value)
+            }
+        }
+    }
+
+    fn read_id3v1(tag: &self/[u8]) {
+        // Unused ID3v1 fields are filled with zeroes.
+        let field = |start: uint, end: uint| {
+            if tag[start] == 0 { view(tag, start, start) } else { view(tag, start, end) }
+        };
+        self.set_if_empty(&mut self.title, TEXT_LATIN1, field(3, 33));
+        self.set_if_empty(&mut self.artist, TEXT_LATIN1, field(33, 63));
+        self.set_if_empty(&mut self.album, TEXT_LATIN1, field(63, 93));
+
+        // ID3v1.1 stores a track number in the last two bytes of the comment.
+        let comment_end = if tag[125] == 0 && tag[126] != 0 { 125 } else { 127 };
+        self.set_if_empty(&mut self.comment, TEXT_LATIN1, field(97, comment_end));
+    }
+}
+
+// Skips a null-terminated string in the given encoding, returning the rest of the data.
+fn skip_terminated_text(encoding: u8, data: &a/[u8]) -> &a/[u8] {
+    let wide = encoding == TEXT_UTF16 || encoding == TEXT_UTF16BE;
+    let step = if wide { 2 } else { 1 };
+    let mut pos = 0;
+    while pos + step <= data.len() {
+        if data[pos] == 0 && (!wide || data[pos + 1] == 0) {
+            return view(data, pos + step, data.len());
+        }
+        pos += step;
+    }
+    return view(data, data.len(), data.len());
+}
+
+// ReplayGain
+//
+// ReplayGain tags give the gain that brings a track or album to a reference loudness, and its
+// peak sample value (1.0 being full scale). The gain is applied to the synthesis filterbank
+// output before it's clamped to 16 bits. ReplayGain 2.0 puts the reference at -18 LUFS as
+// measured by BS.1770, which is how the loudness meter computes track values.
+
+const REPLAYGAIN_REFERENCE: float = -18.0;     // LUFS
+
+enum ReplayGainMode {
+    ReplayGainTrack,
+    ReplayGainAlbum     // Falls back to the track gain if there's no album gain.
+}
+
+// Parses a ReplayGain value such as "-6.20 dB" or "0.988525".
+fn parse_replaygain(text: &TagText) -> option<float> {
+    let text = str::trim(text.to_str());
+    let text = if str::ends_with(str::to_lower(text), "db") {
+        str::trim(str::slice(text, 0, text.len() - 2))
+    } else {
+        text
+    };
+    // float::from_str doesn't accept a leading plus sign.
+    let text = if str::starts_with(text, "+") { str::slice(text, 1, text.len()) } else { text };
+    return float::from_str(text);
+}
+
+// Returns the gain in dB to apply for the given tags: the tagged gain plus the preamp, reduced if
+// need be so that the tagged peak doesn't clip. Without a peak, the gain is limited to 0 dB.
+fn replaygain(tags: &Tags, mode: ReplayGainMode, preamp: float) -> option<float> {
+    let use_album = mode == ReplayGainAlbum && tags.album_gain.is_some();
+    let gain_text = if use_album { &tags.album_gain } else { &tags.track_gain };
+    let peak_text = if use_album { &tags.album_peak } else { &tags.track_peak };
+    let mut gain = match *gain_text {
+        Some(ref text) => match parse_replaygain(text) {
+            Some(gain) => gain + preamp,
+            None => return None
+        },
+        None => return None
+    };
+    let peak = match *peak_text {
+        Some(ref text) => parse_replaygain(text),
+        None => None
+    };
+    let limit = match peak {
+        Some(peak) if peak > 0.0 => -20.0 * float::log10(peak),
+        _ => 0.0
+    };
+    if gain > limit {
+        gain = limit;
+    }
+    return Some(gain);
+}
+
+// VBR headers
+//
+// Some encoders replace the first frame's audio with a Xing/Info or VBRI header carrying the
+// stream length, a seek table and (for LAME-style Info tags) the encoder delay and padding. That
+// frame decodes to garbage, so it's skipped.
+
+enum VbrHeaderKind {
+    Xing,       // "Xing" (VBR) or "Info" (CBR)
+    VBRI
+}
+
+struct VbrInfo {
+    kind: VbrHeaderKind;
+
+    // Size of the header frame, which isn't part of the audio.
+    header_size: uint;
+
+    // Number of audio frames, not counting the header frame as LAME and ffmpeg write it, and
+    // total stream bytes, including the header frame. Zero if unknown.
+    frames: uint;
+    bytes: uint;
+
+    // Seek table: toc[i] is the byte offset of the point i percent of the way through the
+    // stream, relative to the start of the header frame.
+    has_toc: bool;
+    toc: [mut uint]/100;
+
+    // Encoder delay and padding in samples, if the header carried them.
+    has_gapless_info: bool;
+    encoder_delay: uint;
+    encoder_padding: uint;
+}
+
+impl VbrInfo {
+    fn duration_samples() -> uint {
+        self.frames * SAMPLES_PER_FRAME
+    }
+
+    // Returns the byte offset, relative to the header frame, of the frame at the given fraction
+    // (0.0 to 1.0) of the stream, interpolating within the seek table.
+    fn seek_offset(fraction: float) -> option<uint> {
+        if !self.has_toc || self.bytes == 0 {
+            return None;
+        }
+        let percent = float::fmax(0.0, float::fmin(fraction * 100.0, 99.999));
+        let index = percent as uint;
+        let a = self.toc[index] as float;
+        let b = if index < 99 { self.toc[index + 1] as float } else { self.bytes as float };
+        return Some((a + (b - a) * (percent - (index as float))) as uint);
+    }
+}
+
+// Offset of a VBRI header in its frame, 32 bytes after the frame header whatever the mode
+const VBRI_OFFSET: uint = 36;
+
+// Looks for a VBR header in the given first frame.
+fn read_vbr_info(frame: &[u8]) -> option<VbrInfo> {
+    let header = match parse_header(frame) {
+        OK(header) => header,
+        Error(_) => return None
+    };
+    let frame_size = header.frame_size();
+    if frame_size > frame.len() {
+        return None;
+    }
+    let frame = view(frame, 0, frame_size);
+
+    // Encoders place Xing headers where Layer III would put them: after the side information.
+    // Only the defined offsets are checked, so that audio which happens to contain the magic
+    // isn't taken for a header.
+    let xing_pos = 4 + (if header.protected { 2 } else { 0 }) +
+        (if header.mode == Mono { 17 } else { 32 });
+    let mut pos = if has_magic(frame, xing_pos, "Xing") || has_magic(frame, xing_pos, "Info") {
+        xing_pos
+    } else if has_magic(frame, VBRI_OFFSET, "VBRI") {
+        VBRI_OFFSET
+    } else {
+        return None;
+    };
+
+    let mut info = VbrInfo {
+        kind: Xing,
+        header_size: frame_size,
+        frames: 0,
+        bytes: 0,
+        has_toc: false,
+        toc: [ mut 0, ..100 ],
+        has_gapless_info: false,
+        encoder_delay: 0,
+        encoder_padding: 0
+    };
+
+    if has_magic(frame, pos, "VBRI") {
+        return read_vbri(frame, pos, info);
+    }
+
+    // Xing/Info header: a flags word, then the fields present according to the flags.
+    if pos + 8 > frame_size {
+        return None;
+    }
+    let flags = read_u32_be(frame, pos + 4);
+    pos += 8;
+    if (flags & 1) != 0 && pos + 4 <= frame_size {
+        info.frames = read_u32_be(frame, pos);
+        pos += 4;
+    }
+    if (flags & 2) != 0 && pos + 4 <= frame_size {
+        info.bytes = read_u32_be(frame, pos);
+        pos += 4;
+    }
+    if (flags & 4) != 0 && pos + 100 <= frame_size {
+        for uint::range(0, 100) |i| {
+            info.toc[i] = (frame[pos + i] as uint) * info.bytes / 256;
+        }
+        info.has_toc = info.bytes != 0;
+        pos += 100;
+    }
+    if (flags & 8) != 0 {
+        pos += 4;   // Quality indicator
+    }
+
+    // LAME extension: a 9-byte encoder version, then the delay and padding at offset 21 as two
+    // 12-bit values.
+    if pos + 24 <= frame_size && (has_magic(frame, pos, "LAME") || has_magic(frame, pos, "L3.9")) {
+        let value = read_u24_be(frame, pos + 21);
+        info.encoder_delay = value >> 12;
+        info.encoder_padding = value & 0xfff;
+        info.has_gapless_info = true;
+    }
+
+    return Some(info);
+}
+
+fn read_vbri(frame: &[u8], pos: uint, info: VbrInfo) -> option<VbrInfo> {
+    // VBRI header: version, delay, quality, bytes, frames, TOC entry count, TOC scale, TOC entry
+    // size and frames per TOC entry, followed by the TOC of per-entry byte counts.
+    if pos + 26 > frame.len() {
+        return None;
+    }
+    let mut info = info;
+    info.kind = VBRI;
+    info.encoder_delay = read_u16_be(frame, pos + 6);
+    info.bytes = read_u32_be(frame, pos + 10);
+    info.frames = read_u32_be(frame, pos + 14);
+    let entries = read_u16_be(frame, pos + 18);
+    let scale = read_u16_be(frame, pos + 20);
+    let entry_size = read_u16_be(frame, pos + 22);
+    let frames_per_entry = read_u16_be(frame, pos + 24);
+    let toc = view(frame, pos + 26, frame.len());
+
+    if entries > 0 && entry_size >= 1 && entry_size <= 4 && frames_per_entry > 0 &&
+            entries * entry_size <= toc.len() && info.frames > 0 {
+        // Convert the per-entry byte counts into the same percentage table Xing uses.
+        let mut entry = 0;
+        let mut offset = frame.len();
+        for uint::range(0, 100) |i| {
+            let target_frame = i * info.frames / 100;
+            while entry < entries && (entry + 1) * frames_per_entry <= target_frame {
+                let mut size = 0;
+                for uint::range(0, entry_size) |j| {
+                    size = (size << 8) | (toc[entry * entry_size + j] as uint);
+                }
+                offset += size * scale;
+                entry += 1;
+            }
+            info.toc[i] = offset;
+        }
+        info.has_toc = true;
+    }
+    return Some(info);
+}
+
+// Gapless playback
+//
+// The output is delayed by the encoder delay plus DECODER_DELAY, and the last frame is padded.
+// The trimmer drops those samples so that the output is exactly as long as the original.
+
+struct Trimmer {
+    mut skip: uint;         // Samples still to discard at the start
+    mut has_length: bool;
+    mut remaining: uint;    // Samples still to play, if the length is known
+}
+
+fn Trimmer(encoder_delay: uint, encoder_padding: uint, frames: uint) -> Trimmer {
+    let total = frames * SAMPLES_PER_FRAME;
+    let trimmed = encoder_delay + encoder_padding;
+    Trimmer {
+        skip: encoder_delay + DECODER_DELAY,
+        has_length: frames > 0,
+        remaining: if total > trimmed { total - trimmed } else { 0 }
+    }
+}
+
+impl Trimmer {
+    // Trims a block of interleaved output with the given number of channels, returning the part
+    // to play.
+    fn trim(pcm: &a/[mut i16], channels: uint) -> &a/[mut i16] {
+        let mut start = pcm.len() / channels;
+        if self.skip < start {
+            start = self.skip;
+        }
+        self.skip -= start;
+
+        let mut end = pcm.len() / channels;
+        if self.has_length && end - start > self.remaining {
+            end = start + self.remaining;
+        }
+        if self.has_length {
+            self.remaining -= end - start;
+        }
+        return mut_view(pcm, start * channels, end * channels);
+    }
+
+    // Trims the output of MP2Stream::flush(). If the length is unknown, only the samples still
+    // held in the filterbank are kept.
+    fn trim_flush(pcm: &a/[mut i16], channels: uint) -> &a/[mut i16] {
+        if !self.has_length {
+            self.has_length = true;
+            self.remaining = DECODER_DELAY;
+        }
+        return self.trim(pcm, channels);
+    }
+}
+
+// Resampling
+//
+// The resampler converts the output to a fixed rate with a windowed-sinc lowpass filter. Its
+// coefficients are tabulated for RESAMPLER_PHASES positions between input samples and
+// interpolated linearly in between, so any pair of rates works. Nothing is allocated: input is
+// kept in a fixed buffer of history plus one block, and output is handed out in fixed-size
+// chunks.
+
+enum ResampleQuality {
+    ResampleLow,        // 8 taps
+    ResampleMedium,     // 24 taps
+    ResampleHigh        // 48 taps
+}
+
+const RESAMPLER_PHASES: uint = 256;
+const RESAMPLER_MAX_TAPS: uint = 48;
+const RESAMPLER_MAX_CHANNELS: uint = 6;
+const RESAMPLER_BLOCK: uint = 1152;             // Input frames taken at a time
+const RESAMPLER_OUTPUT: uint = 1024;            // Output frames per chunk
+
+struct Resampler {
+    out_rate: uint;
+    taps: uint;
+    rolloff: float;     // Cutoff as a fraction of the lower Nyquist frequency
+
+    // Input format, and coefficients for RESAMPLER_PHASES + 1 phases (2^-15 units)
+    mut in_rate: uint;
+    mut channels: uint;
+    coeffs: [mut i32]/12336;
+
+    // Input frames, planar, starting with history. The next output frame lies between frames
+    // pos and pos + 1, frac / out_rate of the way.
+    input: [mut i32]/7200;
+    mut input_len: uint;
+    mut pos: uint;
+    mut frac: uint;
+
+    // Output frames, interleaved
+    output: [mut i16]/6144;
+    mut output_len: uint;
+}
+
+fn Resampler(out_rate: uint, quality: ResampleQuality) -> Resampler {
+    let (taps, rolloff) = match quality {
+        ResampleLow => (8, 0.80),
+        ResampleMedium => (24, 0.91),
+        ResampleHigh => (48, 0.95)
+    };
+    Resampler {
+        out_rate: out_rate,
+        taps: taps,
+        rolloff: rolloff,
+        in_rate: 0,
+        channels: 0,
+        coeffs: [ mut 0, ..12336 ],     // (RESAMPLER_PHASES + 1) * RESAMPLER_MAX_TAPS
+        input: [ mut 0, ..7200 ],       // RESAMPLER_MAX_CHANNELS * (RESAMPLER_BLOCK + taps)
+        input_len: 0,
+        pos: 0,
+        frac: 0,
+        output: [ mut 0, ..6144 ],      // RESAMPLER_MAX_CHANNELS * RESAMPLER_OUTPUT
+        output_len: 0
+    }
+}
+
+// Returns the unnormalized weight of tap k at the given phase: a sinc lowpass with the given
+// cutoff, in a Blackman window. Tap k applies to the input frame k - (half - 1) frames after the
+// one at or before the output position.
+fn resampler_tap(cutoff: float, half: float, phase: uint, k: uint) -> float {
+    let pi = float::consts::pi;
+    let d = (phase as float) / (RESAMPLER_PHASES as float) + half - 1.0 - (k as float);
+    let sinc = if d == 0.0 { 2.0 * cutoff } else { float::sin(2.0 * pi * cutoff * d) / (pi * d) };
+    let x = (d + half) / (2.0 * half);
+    let window = 0.42 - 0.5 * cos(2.0 * pi * x) + 0.08 * cos(4.0 * pi * x);
+    return sinc * window;
+}
+
+impl Resampler {
+    // Sets the input format, designing the filter and clearing the history if it changed.
+    fn set_input(in_rate: uint, channels: uint) {
+        assert channels <= RESAMPLER_MAX_CHANNELS;
+        if in_rate == self.in_rate && channels == self.channels {
+            return;
+        }
+        self.in_rate = in_rate;
+        self.channels = channels;
+        self.reset();
+
+        // Cut off below the lower of the two Nyquist frequencies, in cycles per input sample.
+        let ratio = (self.out_rate as float) / (in_rate as float);
+        let cutoff = 0.5 * self.rolloff * (if ratio < 1.0 { ratio } else { 1.0 });
+        let half = (self.taps / 2) as float;
+        for uint::range(0, RESAMPLER_PHASES + 1) |phase| {
+            let row = phase * self.taps;
+            let mut sum = 0.0;
+            for uint::range(0, self.taps) |k| {
+                sum += resampler_tap(cutoff, half, phase, k);
+            }
+            for uint::range(0, self.taps) |k| {
+                // Normalize each phase to unity gain at DC.
+                let tap = resampler_tap(cutoff, half, phase, k);
+                self.coeffs[row + k] = (32768.0 * tap / sum) as i32;
+            }
+        }
+    }
+
+    // Clears the history, as at the start of a stream.
+    fn reset() {
+        // Start with enough silence that the first output frame is centred on the first input.
+        self.input_len = self.taps / 2 - 1;
+        for uint::range(0, RESAMPLER_MAX_CHANNELS * (RESAMPLER_BLOCK + RESAMPLER_MAX_TAPS)) |i| {
+            self.input[i] = 0;
+        }
+        self.pos = self.input_len;
+        self.frac = 0;
+        self.output_len = 0;
+    }
+
+    // Resamples a block of interleaved input, calling the given function with each chunk of
+    // output. Output that doesn't fill a chunk is held until the next call or finish().
+    fn process(pcm: &[i16], f: fn(&[i16])) {
+        if self.in_rate == self.out_rate {
+            f(pcm);
+            return;
+        }
+        let stride = RESAMPLER_BLOCK + RESAMPLER_MAX_TAPS;
+        let mut pcm = pcm;
+        while pcm.len() > 0 {
+            // Append as much input as fits.
+            let room = stride - self.input_len;
+            let count = uint::min(room, pcm.len() / self.channels);
+            for uint::range(0, count) |i| {
+                for uint::range(0, self.channels) |ch| {
+                    let sample = pcm[i * self.channels + ch] as i32;
+                    self.input[ch * stride + self.input_len + i] = sample;
+                }
+            }
+            self.input_len += count;
+            pcm = view(pcm, count * self.channels, pcm.len());
+            self.run(f);
+            if count == 0 {
+                break;
+            }
+        }
+    }
+
+    // Produces all the output the input so far allows, then drops the input no longer needed.
+    fn run(f: fn(&[i16])) {
+        let stride = RESAMPLER_BLOCK + RESAMPLER_MAX_TAPS;
+        let half = self.taps / 2;
+        while self.pos + half < self.input_len {
+            // Find the two phases either side of the output position.
+            let position = (self.frac as u64) * (RESAMPLER_PHASES as u64);
+            let phase = (position / (self.out_rate as u64)) as uint;
+            let weight = ((position % (self.out_rate as u64)) * 32768 /
+                (self.out_rate as u64)) as i64;
+            let row = phase * self.taps;
+            let next_row = row + self.taps;
+            let start = self.pos + 1 - half;
+
+            for uint::range(0, self.channels) |ch| {
+                let mut sum: i64 = 0;
+                for uint::range(0, self.taps) |k| {
+                    let c0 = self.coeffs[row + k] as i64;
+                    let c1 = self.coeffs[next_row + k] as i64;
+                    let coeff = c0 + (((c1 - c0) * weight) >> 15);
+                    sum += coeff * (self.input[ch * stride + start + k] as i64);
+                }
+                let mut value = (sum + 16384) >> 15;
+                if value < -32768 {
+                    value = -32768;
+                }
+                if value > 32767 {
+                    value = 32767;
+                }
+                self.output[self.output_len * self.channels + ch] = value as i16;
+            }
+            self.output_len += 1;
+            if self.output_len == RESAMPLER_OUTPUT {
+                f(view(self.output, 0, self.output_len * self.channels));
+                self.output_len = 0;
+            }
+
+            self.frac += self.in_rate;
+            while self.frac >= self.out_rate {
+                self.frac -= self.out_rate;
+                self.pos += 1;
+            }
+        }
+
+        // Keep the frames the next output frame needs.
+        let first = uint::min(self.pos + 1 - half, self.input_len);
+        for uint::range(0, self.channels) |ch| {
+            for uint::range(first, self.input_len) |i| {
+                self.input[ch * stride + i - first] = self.input[ch * stride + i];
+            }
+        }
+        self.input_len -= first;
+        self.pos -= first;
+    }
+
+    // Pushes out the rest of the output, then resets for a new stream.
+    fn finish(f: fn(&[i16])) {
+        if self.in_rate != self.out_rate && self.channels > 0 {
+            let silence = [ 0i16, ..288 ];      // RESAMPLER_MAX_TAPS * RESAMPLER_MAX_CHANNELS
+            self.process(view(silence, 0, (self.taps / 2) * self.channels), f);
+            if self.output_len > 0 {
+                f(view(self.output, 0, self.output_len * self.channels));
+            }
+        }
+        self.reset();
+    }
+}
+
+// Loudness measurement
+//
+// The loudness meter follows ITU-R BS.1770 and EBU R 128. Each channel is K-weighted (a high
+// shelf and a highpass), squared and averaged over 100 ms steps. Momentary loudness covers the
+// last 400 ms and short-term loudness the last 3 s. Integrated loudness gates 400 ms blocks at
+// -70 LUFS and then 10 LU below the mean; loudness range takes the 10th to 95th percentile of
+// short-term values gated at -70 LUFS and 20 LU below the mean. Blocks are kept in histograms of
+// 0.1 LU bins, so memory use doesn't grow with the stream. True peak is measured after 4x
+// oversampling.
+
+const LOUDNESS_BINS: uint = 1000;                   // -70 to +30 LUFS
+const LOUDNESS_MIN: float = -70.0;
+const LOUDNESS_SUBBLOCKS_MOMENTARY: uint = 4;       // 400 ms
+const LOUDNESS_SUBBLOCKS_SHORT_TERM: uint = 30;     // 3 s
+const TRUE_PEAK_TAPS: uint = 12;                    // Per phase; the filter has 48.
+const TRUE_PEAK_PHASES: uint = 4;
+
+struct LoudnessMeter {
+    mut sample_rate: uint;
+    mut channels: uint;
+
+    // K-weighting filter coefficients (b0, b1, b2, a1, a2 for each stage) and per-channel state
+    // (two inputs and two outputs per stage)
+    shelf: [mut float]/5;
+    highpass: [mut float]/5;
+    filter_state: [mut float]/48;
+
+    // Channel-weighted energy of the current 100 ms sub-block so far, and of the last 30
+    mut subblock_energy: float;
+    mut subblock_len: uint;
+    subblocks: [mut float]/30;
+    mut subblock_count: uint;
+
+    // Histograms of 400 ms block energies and of short-term values
+    block_histogram: [mut uint]/1000;
+    short_term_histogram: [mut uint]/1000;
+
+    mut momentary: float;
+    mut short_term: float;
+    mut max_momentary: float;
+    mut max_short_term: float;
+
+    // True peak: the oversampling filter, the last TRUE_PEAK_TAPS samples of each channel and
+    // the peak so far (1.0 is full scale)
+    true_peak_filter: [mut float]/48;
+    true_peak_history: [mut float]/72;
+    mut true_peak: float;
+
+    // Sample peak, as ReplayGain peaks are (1.0 is full scale)
+    mut sample_peak: float;
+}
+
+fn LoudnessMeter() -> LoudnessMeter {
+    let meter = LoudnessMeter {
+        sample_rate: 0,
+        channels: 0,
+        shelf: [ mut 0.0, ..5 ],
+        highpass: [ mut 0.0, ..5 ],
+        filter_state: [ mut 0.0, ..48 ],
+        subblock_energy: 0.0,
+        subblock_len: 0,
+        subblocks: [ mut 0.0, ..30 ],
+        subblock_count: 0,
+        block_histogram: [ mut 0, ..1000 ],
+        short_term_histogram: [ mut 0, ..1000 ],
+        momentary: float::neg_infinity,
+        short_term: float::neg_infinity,
+        max_momentary: float::neg_infinity,
+        max_short_term: float::neg_infinity,
+        true_peak_filter: [ mut 0.0, ..48 ],
+        true_peak_history: [ mut 0.0, ..72 ],
+        true_peak: 0.0,
+        sample_peak: 0.0
+    };
+
+    // The oversampling filter: a windowed sinc, phase p interpolating p / 4 of the way from the
+    // sixth to the seventh of the last 12 samples.
+    let pi = float::consts::pi;
+    for uint::range(0, TRUE_PEAK_PHASES) |phase| {
+        for uint::range(0, TRUE_PEAK_TAPS) |k| {
+            let d = (phase as float) / (TRUE_PEAK_PHASES as float) + 5.0 - (k as float);
+            let sinc = if d == 0.0 { 1.0 } else { float::sin(pi * d) / (pi * d) };
+            let x = (d + 6.0) / 12.0;
+            let window = 0.42 - 0.5 * cos(2.0 * pi * x) + 0.08 * cos(4.0 * pi * x);
+            meter.true_peak_filter[phase * TRUE_PEAK_TAPS + k] = sinc * window;
+        }
+    }
+    return meter;
+}
+
+// Converts a mean square to loudness in LUFS.
+fn energy_to_loudness(energy: float) -> float {
+    if energy <= 0.0 { float::neg_infinity } else { -0.691 + 10.0 * float::log10(energy) }
+}
+
+// Returns the histogram bin for the given loudness, if it's in range.
+fn loudness_bin(loudness: float) -> option<uint> {
+    if loudness < LOUDNESS_MIN {
+        return None;
+    }
+    let bin = ((loudness - LOUDNESS_MIN) * 10.0) as uint;
+    return Some(if bin >= LOUDNESS_BINS { LOUDNESS_BINS - 1 } else { bin });
+}
+
+// Returns the loudness at the middle of a histogram bin.
+fn bin_loudness(bin: uint) -> float {
+    LOUDNESS_MIN + ((bin as float) + 0.5) / 10.0
+}
+
+// Returns the mean square corresponding to the middle of a histogram bin.
+fn bin_energy(bin: uint) -> float {
+    float::pow(10.0, (bin_loudness(bin) + 0.691) / 10.0)
+}
+
+impl LoudnessMeter {
+    // Sets the input format. The filters are reset when it changes, but measurements so far
+    // are kept.
+    fn set_format(sample_rate: uint, channels: uint) {
+        assert channels <= 6;
+        if sample_rate == self.sample_rate && channels == self.channels {
+            return;
+        }
+        self.sample_rate = sample_rate;
+        self.channels = channels;
+        for uint::range(0, 48) |i| {
+            self.filter_state[i] = 0.0;
+        }
+        for uint::range(0, 72) |i| {
+            self.true_peak_history[i] = 0.0;
+        }
+        self.subblock_energy = 0.0;
+        self.subblock_len = 0;
+
+        // K-weighting coefficients for this sample rate, from the BS.1770 analog prototypes
+        let rate = sample_rate as float;
+        let pi = float::consts::pi;
+        let k = float::tan(pi * 1681.974450955533 / rate);
+        let q = 0.7071752369554196;
+        let vh = float::pow(10.0, 3.999843853973347 / 20.0);
+        let vb = float::pow(vh, 0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        self.shelf[0] = (vh + vb * k / q + k * k) / a0;
+        self.shelf[1] = 2.0 * (k * k - vh) / a0;
+        self.shelf[2] = (vh - vb * k / q + k * k) / a0;
+        self.shelf[3] = 2.0 * (k * k - 1.0) / a0;
+        self.shelf[4] = (1.0 - k / q + k * k) / a0;
+
+        let k = float::tan(pi * 38.13547087602444 / rate);
+        let q = 0.5003270373238773;
+        let a0 = 1.0 + k / q + k * k;
+        self.highpass[0] = 1.0;
+        self.highpass[1] = -2.0;
+        self.highpass[2] = 1.0;
+        self.highpass[3] = 2.0 * (k * k - 1.0) / a0;
+        self.highpass[4] = (1.0 - k / q + k * k) / a0;
+    }
+
+    // Measures a block of interleaved output in the current format.
+    fn add(pcm: &[i16]) {
+        let subblock_size = self.sample_rate / 10;
+        for uint::range(0, pcm.len() / self.channels) |i| {
+            let mut energy = 0.0;
+            for uint::range(0, self.channels) |ch| {
+                let x = (pcm[i * self.channels + ch] as float) / 32768.0;
+                self.measure_peak(ch, x);
+                self.sample_peak = float::fmax(self.sample_peak, float::abs(x));
+
+                // Channel weights: the LFE channel doesn't count and surround channels count
+                // 1.5 dB more.
+                let weight = if self.channels != 6 || ch < 3 { 1.0 } else if ch == 3 { 0.0 } else {
+                    1.41
+                };
+                let y = self.k_weight(ch, x);
+                energy += weight * y * y;
+            }
+            self.subblock_energy += energy;
+            self.subblock_len += 1;
+            if self.subblock_len == subblock_size {
+                self.end_subblock(self.subblock_energy / (subblock_size as float));
+                self.subblock_energy = 0.0;
+                self.subblock_len = 0;
+            }
+        }
+    }
+
+    // Runs a sample through a channel's K-weighting filter.
+    fn k_weight(ch: uint, x: float) -> float {
+        let state = ch * 8;
+        let s = &self.shelf;
+        let y = s[0] * x + s[1] * self.filter_state[state] + s[2] * self.filter_state[state + 1] -
+            s[3] * self.filter_state[state + 2] - s[4] * self.filter_state[state + 3];
+        self.filter_state[state + 1] = self.filter_state[state];
+        self.filter_state[state] = x;
+        self.filter_state[state + 3] = self.filter_state[state + 2];
+        self.filter_state[state + 2] = y;
+
+        let h = &self.highpass;
+        let z = h[0] * y + h[1] * self.filter_state[state + 4] +
+            h[2] * self.filter_state[state + 5] - h[3] * self.filter_state[state + 6] -
+            h[4] * self.filter_state[state + 7];
+        self.filter_state[state + 5] = self.filter_state[state + 4];
+        self.filter_state[state + 4] = y;
+        self.filter_state[state + 7] = self.filter_state[state + 6];
+        self.filter_state[state + 6] = z;
+        return z;
+    }
+
+    // Updates the true peak with a new sample of a channel, interpolating between the middle
+    // two of the last TRUE_PEAK_TAPS samples.
+    fn measure_peak(ch: uint, x: float) {
+        let history = ch * TRUE_PEAK_TAPS;
+        for uint::range(0, TRUE_PEAK_TAPS - 1) |k| {
+            self.true_peak_history[history + k] = self.true_peak_history[history + k + 1];
+        }
+        self.true_peak_history[history + TRUE_PEAK_TAPS - 1] = x;
+        for uint::range(0, TRUE_PEAK_PHASES) |phase| {
+            let mut value = 0.0;
+            for uint::range(0, TRUE_PEAK_TAPS) |k| {
+                value += self.true_peak_filter[phase * TRUE_PEAK_TAPS + k] *
+                    self.true_peak_history[history + k];
+            }
+            let value = float::abs(value);
+            if value > self.true_peak {
+                self.true_peak = value;
+            }
+        }
+    }
+
+    // Finishes a 100 ms sub-block with the given energy, updating the momentary and short-term
+    // loudness.
+    fn end_subblock(energy: float) {
+        for uint::range(0, LOUDNESS_SUBBLOCKS_SHORT_TERM - 1) |i| {
+            self.subblocks[i] = self.subblocks[i + 1];
+        }
+        self.subblocks[LOUDNESS_SUBBLOCKS_SHORT_TERM - 1] = energy;
+        self.subblock_count += 1;
+
+        if self.subblock_count >= LOUDNESS_SUBBLOCKS_MOMENTARY {
+            let mut sum = 0.0;
+            for uint::range(LOUDNESS_SUBBLOCKS_SHORT_TERM - LOUDNESS_SUBBLOCKS_MOMENTARY,
+                            LOUDNESS_SUBBLOCKS_SHORT_TERM) |i| {
+                sum += self.subblocks[i];
+            }
+            self.momentary = energy_to_loudness(sum / (LOUDNESS_SUBBLOCKS_MOMENTARY as float));
+            self.max_momentary = float::fmax(self.max_momentary, self.momentary);
+            match loudness_bin(self.momentary) {
+                Some(bin) => self.block_histogram[bin] += 1,
+                None => {}
+            }
+        }
+        if self.subblock_count >= LOUDNESS_SUBBLOCKS_SHORT_TERM {
+            let mut sum = 0.0;
+            for uint::range(0, LOUDNESS_SUBBLOCKS_SHORT_TERM) |i| {
+                sum += self.subblocks[i];
+            }
+            self.short_term = energy_to_loudness(sum / (LOUDNESS_SUBBLOCKS_SHORT_TERM as float));
+            self.max_short_term = float::fmax(self.max_short_term, self.short_term);
+            match loudness_bin(self.short_term) {
+                Some(bin) => self.short_term_histogram[bin] += 1,
+                None => {}
+            }
+        }
+    }
+
+    // Returns the integrated loudness in LUFS.
+    fn integrated() -> float {
+        let gate = self.relative_gate(&self.block_histogram, 10.0);
+        let mut energy = 0.0;
+        let mut count = 0;
+        for uint::range(0, LOUDNESS_BINS) |bin| {
+            if bin_loudness(bin) >= gate {
+                energy += (self.block_histogram[bin] as float) * bin_energy(bin);
+                count += self.block_histogram[bin];
+            }
+        }
+        return if count == 0 { float::neg_infinity } else {
+            energy_to_loudness(energy / (count as float))
+        };
+    }
+
+    // Returns the loudness range in LU.
+    fn loudness_range() -> float {
+        let gate = self.relative_gate(&self.short_term_histogram, 20.0);
+        let mut count = 0;
+        for uint::range(0, LOUDNESS_BINS) |bin| {
+            if bin_loudness(bin) >= gate {
+                count += self.short_term_histogram[bin];
+            }
+        }
+        if count == 0 {
+            return 0.0;
+        }
+
+        // Find the 10th and 95th percentiles.
+        let low_index = count / 10;
+        let high_index = count * 95 / 100;
+        let mut low = 0.0;
+        let mut high = 0.0;
+        let mut seen = 0;
+        for uint::range(0, LOUDNESS_BINS) |bin| {
+            if bin_loudness(bin) < gate || self.short_term_histogram[bin] == 0 {
+                loop;
+            }
+            let next = seen + self.short_term_histogram[bin];
+            if seen <= low_index && low_index < next {
+                low = bin_loudness(bin);
+            }
+            if seen <= high_index && high_index < next {
+                high = bin_loudness(bin);
+            }
+            seen = next;
+        }
+        return high - low;
+    }
+
+    // Returns the relative gate for a histogram: the given number of LU below the mean of the
+    // blocks above the absolute gate.
+    fn relative_gate(histogram: &[mut uint]/1000, offset: float) -> float {
+        let mut energy = 0.0;
+        let mut count = 0;
+        for uint::range(0, LOUDNESS_BINS) |bin| {
+            energy += (histogram[bin] as float) * bin_energy(bin);
+            count += histogram[bin];
+        }
+        if count == 0 {
+            return float::infinity;
+        }
+        return energy_to_loudness(energy / (count as float)) - offset;
+    }
+
+    // Returns the true peak in dBTP.
+    fn true_peak_db() -> float {
+        if self.true_peak <= 0.0 {
+            float::neg_infinity
+        } else {
+            20.0 * float::log10(self.true_peak)
+        }
+    }
+
+    // Prints a loudness report.
+    fn report() {
+        println(fmt!("Integrated loudness: %.1f LUFS", self.integrated()));
+        println(fmt!("Loudness range: %.1f LU", self.loudness_range()));
+        println(fmt!("Maximum momentary loudness: %.1f LUFS", self.max_momentary));
+        println(fmt!("Maximum short-term loudness: %.1f LUFS", self.max_short_term));
+        println(fmt!("True peak: %.1f dBTP", self.true_peak_db()));
+        println(fmt!("ReplayGain track gain: %.2f dB", REPLAYGAIN_REFERENCE - self.integrated()));
+        println(fmt!("ReplayGain track peak: %.6f", self.sample_peak));
+    }
+}
+
+// DAB programme associated data
+//
+// DAB puts programme associated data (PAD) at the end of each Layer II frame: the two F-PAD bytes
+// come last, preceded by the ScF-CRC and, before that, the X-PAD, which is stored backwards.
+// X-PAD carries Dynamic Label text and MOT objects such as slideshow images (ETSI EN 300 401).
+
+// Size of the ScF-CRC field: 4 bytes at 56 kbit/s per channel and above, 2 bytes below.
+fn scf_crc_size(header: &FrameHeader) -> uint {
+    if header.bit_rate() / header.num_channels() >= 56 { 4 } else { 2 }
+}
+
+// Adds the low bits of a value to a CRC-8 with the generator x^8 + x^4 + x^3 + x^2 + 1, as used
+// for the DAB ScF-CRC.
+fn crc8_update(crc: uint, value: uint, bit_count: uint) -> uint {
+    let mut crc = crc;
+    for uint::range(0, bit_count) |i| {
+        let bit = (value >> (bit_count - 1 - i)) & 1;
+        crc = if ((crc >> 7) ^ bit) != 0 { ((crc << 1) ^ 0x1d) & 0xff } else { (crc << 1) & 0xff };
+    }
+    return crc;
+}
+
+// CRC-16-CCITT (x^16 + x^12 + x^5 + 1) with the result inverted, as used for DAB data groups.
+fn crc16_ccitt(data: &[u8]) -> uint {
+    let mut crc = 0xffff;
+    for data.each |byte| {
+        crc ^= (*byte as uint) << 8;
+        for 8.times {
+            crc = if (crc & 0x8000) != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+        crc &= 0xffff;
+    }
+    return crc ^ 0xffff;
+}
+
+// Variable-size X-PAD subfield lengths, by length indicator
+const XPAD_LENGTHS: [uint]/8 = [ 4, 6, 8, 12, 16, 24, 32, 48 ];
+
+// Largest X-PAD: four contents indicators and four 48-byte subfields
+const XPAD_MAX: uint = 196;
+
+// X-PAD application types
+const APP_END_MARKER: u8 = 0;
+const APP_DATA_GROUP_LENGTH: u8 = 1;
+const APP_DLS_START: u8 = 2;
+const APP_DLS_CONTINUATION: u8 = 3;
+const APP_MOT_START: u8 = 12;
+const APP_MOT_CONTINUATION: u8 = 13;
+
+// MOT data group types
+const MOT_HEADER: u8 = 3;
+const MOT_BODY: u8 = 4;
+
+// MOT content type for images; the subtype is 0 for GIF, 1 for JPEG, 2 for BMP and 3 for PNG.
+const MOT_CONTENT_IMAGE: uint = 2;
+
+// Returns the application type of X-PAD data that continues the given application.
+fn continuation_app(app: u8) -> u8 {
+    match app {
+        APP_DLS_START => APP_DLS_CONTINUATION,
+        APP_MOT_START => APP_MOT_CONTINUATION,
+        _ => app
+    }
+}
+
+enum PadEvent {
+    // A complete dynamic label, with its character set (0 is EBU Latin, 15 is UTF-8).
+    DynamicLabel(u8, &self/[u8]),
+    // The receiver should clear the label.
+    ClearLabel,
+    // A complete MOT object: content type, content subtype and body.
+    MotObject(uint, uint, &self/[u8])
+}
+
+struct PadDecoder {
+    // X-PAD of the current frame in transmission order
+    xpad: [mut u8]/196;
+
+    // The application continued by X-PAD without contents indicators, and the total subfield
+    // length of the last variable-size X-PAD.
+    mut last_app: u8;
+    mut last_length: uint;
+
+    // Length of the next MOT data group, from the data group length indicator
+    mut next_group_length: uint;
+
+    // The data group being reassembled. Its length is zero if unknown.
+    group: [mut u8]/16384;
+    mut group_app: u8;
+    mut group_size: uint;
+    mut group_length: uint;
+
+    // The dynamic label being reassembled
+    label: [mut u8]/128;
+    mut label_size: uint;
+    mut label_charset: u8;
+    mut label_toggle: u8;
+    mut label_next_segment: uint;
+    mut label_shown_toggle: u8;     // 2 if no label has been shown
+
+    // The MOT header and body being reassembled
+    mut header_transport_id: uint;
+    mut header_valid: bool;
+    mut content_type: uint;
+    mut content_subtype: uint;
+    body: [mut u8]/65536;
+    mut body_size: uint;
+    mut body_transport_id: uint;
+    mut body_next_segment: uint;
+}
+
+fn PadDecoder() -> PadDecoder {
+    PadDecoder {
+        xpad: [ mut 0, ..196 ],
+        last_app: APP_END_MARKER,
+        last_length: 0,
+        next_group_length: 0,
+        group: [ mut 0, ..16384 ],
+        group_app: APP_END_MARKER,
+        group_size: 0,
+        group_length: 0,
+        label: [ mut 0, ..128 ],
+        label_size: 0,
+        label_charset: 0,
+        label_toggle: 0,
+        label_next_segment: 0,
+        label_shown_toggle: 2,
+        header_transport_id: 0,
+        header_valid: false,
+        content_type: 0,
+        content_subtype: 0,
+        body: [ mut 0, ..65536 ],
+        body_size: 0,
+        body_transport_id: 0,
+        body_next_segment: 0
+    }
+}
+
+impl PadDecoder {
+    // Decodes the PAD at the end of a frame's ancillary data, calling the given function for each
+    // complete label or object.
+    fn decode(ancillary: &[u8], scf_crc_size: uint, f: fn(&PadEvent)) {
+        let len = ancillary.len();
+        if len < 2 + scf_crc_size {
+            return;
+        }
+        let fpad0 = ancillary[len - 2];
+        let fpad1 = ancillary[len - 1];
+        if (fpad0 >> 6) != 0 {
+            return;     // Only F-PAD type 0 carries X-PAD.
+        }
+        let xpad_indicator = (fpad0 >> 4) & 3;
+        let has_ci = (fpad1 & 2) != 0;
+
+        // Put the X-PAD back in transmission order.
+        let mut xpad_size = len - 2 - scf_crc_size;
+        if xpad_size > XPAD_MAX {
+            xpad_size = XPAD_MAX;
+        }
+        for uint::range(0, xpad_size) |i| {
+            self.xpad[i] = ancillary[len - 3 - scf_crc_size - i];
+        }
+
+        match xpad_indicator {
+            1 if xpad_size >= 4 => {
+                // Short X-PAD: an optional contents indicator and 3 or 4 bytes of data.
+                if has_ci {
+                    let app = self.xpad[0] & 31;
+                    self.subfield(app, 1, 4, f);
+                    self.last_app = continuation_app(app);
+                } else {
+                    self.subfield(self.last_app, 0, 4, f);
+                }
+            }
+            2 => {
+                // Variable-size X-PAD: up to four contents indicators, then the subfields.
+                if !has_ci {
+                    let end = uint::min(self.last_length, xpad_size);
+                    self.subfield(self.last_app, 0, end, f);
+                    return;
+                }
+
+                let apps = [ mut 0u8, 0, 0, 0 ];
+                let lengths = [ mut 0u, 0, 0, 0 ];
+                let mut count = 0;
+                let mut pos = 0;
+                while count < 4 && pos < xpad_size {
+                    let ci = self.xpad[pos];
+                    pos += 1;
+                    if (ci & 31) == APP_END_MARKER {
+                        break;
+                    }
+                    apps[count] = ci & 31;
+                    lengths[count] = XPAD_LENGTHS[ci >> 5];
+                    count += 1;
+                }
+
+                self.last_length = 0;
+                for uint::range(0, count) |i| {
+                    let end = uint::min(pos + lengths[i], xpad_size);
+                    self.subfield(apps[i], pos, end, f);
+                    self.last_app = continuation_app(apps[i]);
+                    self.last_length += lengths[i];
+                    pos = end;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Handles the X-PAD bytes between start and end, which belong to the given application.
+    fn subfield(app: u8, start: uint, end: uint, f: fn(&PadEvent)) {
+        if start >= end {
+            return;
+        }
+        match app {
+            APP_DATA_GROUP_LENGTH if end - start >= 2 => {
+                self.next_group_length = ((self.xpad[start] as uint & 0x3f) << 8) |
+                    (self.xpad[start + 1] as uint);
+                return;
+            }
+            APP_DLS_START => {
+                self.group_app = APP_DLS_START;
+                self.group_size = 0;
+                self.group_length = 0;
+            }
+            APP_MOT_START => {
+                // A data group holds at least a 2-byte header, and must fit the buffer; drop
+                // groups whose length says otherwise.
+                let length = self.next_group_length;
+                if length < 2 || length > self.group.len() {
+                    self.group_app = APP_END_MARKER;
+                    return;
+                }
+                self.group_app = APP_MOT_START;
+                self.group_size = 0;
+                self.group_length = length;
+            }
+            APP_DLS_CONTINUATION if self.group_app == APP_DLS_START => {}
+            APP_MOT_CONTINUATION if self.group_app == APP_MOT_START => {}
+            _ => return
+        }
+
+        for uint::range(start, end) |i| {
+            if self.group_size < self.group.len() {
+                self.group[self.group_size] = self.xpad[i];
+                self.group_size += 1;
+            }
+        }
+
+        // Dynamic label data groups give their own length: a 2-byte prefix, up to 16 characters
+        // (none for commands) and a CRC.
+        if self.group_app == APP_DLS_START && self.group_length == 0 {
+            self.group_length = if (self.group[0] & 0x10) != 0 {
+                4
+            } else {
+                2 + (self.group[0] as uint & 15) + 1 + 2
+            };
+        }
+
+        if self.group_length == 0 || self.group_size < self.group_length {
+            return;
+        }
+        let length = self.group_length;
+        let app = self.group_app;
+        self.group_app = APP_END_MARKER;
+
+        // Dynamic label segments always end with a CRC; MOT data groups flag it in their header.
+        let has_crc = app == APP_DLS_START || (self.group[0] & 0x40) != 0;
+        if has_crc {
+            if length < 4 {
+                return;
+            }
+            if crc16_ccitt(view(self.group, 0, length - 2)) != read_u16_be(self.group, length - 2) {
+                return;
+            }
+        }
+        if app == APP_DLS_START {
+            self.dynamic_label_segment(length, f);
+        } else {
+            self.mot_data_group(length, f);
+        }
+    }
+
+    fn dynamic_label_segment(length: uint, f: fn(&PadEvent)) {
+        let toggle = self.group[0] >> 7;
+        let first = (self.group[0] & 0x40) != 0;
+        let last = (self.group[0] & 0x20) != 0;
+        if (self.group[0] & 0x10) != 0 {
+            // Command. Only "clear display" is of interest.
+            if (self.group[0] & 15) == 1 {
+                self.label_shown_toggle = 2;
+                f(&ClearLabel);
+            }
+            return;
+        }
+
+        let segment = if first { 0 } else { (self.group[1] as uint >> 4) & 7 };
+        if first {
+            self.label_size = 0;
+            self.label_charset = self.group[1] >> 4;
+            self.label_toggle = toggle;
+        } else if toggle != self.label_toggle || segment != self.label_next_segment {
+            return;     // A segment was lost.
+        }
+        for uint::range(2, length - 2) |i| {
+            self.label[self.label_size] = self.group[i];
+            self.label_size += 1;
+        }
+        self.label_next_segment = segment + 1;
+
+        // Labels are repeated continuously; only report one when it changes.
+        if last && self.label_toggle != self.label_shown_toggle {
+            self.label_shown_toggle = self.label_toggle;
+            f(&DynamicLabel(self.label_charset, view(self.label, 0, self.label_size)));
+        }
+    }
+
+    fn mot_data_group(length: uint, f: fn(&PadEvent)) {
+        // Data group header
+        let group = view(self.group, 0, length);
+        let has_extension = (group[0] & 0x80) != 0;
+        let has_crc = (group[0] & 0x40) != 0;
+        let has_segment = (group[0] & 0x20) != 0;
+        let has_user_access = (group[0] & 0x10) != 0;
+        let group_type = group[0] & 15;
+        let mut pos = if has_extension { 4 } else { 2 };
+        let end = if has_crc { length - 2 } else { length };
+
+        // Session header
+        let mut last = false;
+        let mut segment = 0;
+        if has_segment {
+            if pos + 2 > end { return; }
+            last = (group[pos] & 0x80) != 0;
+            segment = read_u16_be(group, pos) & 0x7fff;
+            pos += 2;
+        }
+        let mut transport_id = 0;
+        if has_user_access {
+            if pos + 1 > end { return; }
+            let has_transport_id = (group[pos] & 0x10) != 0;
+            let address_length = group[pos] as uint & 15;
+            if has_transport_id && pos + 3 <= end {
+                transport_id = read_u16_be(group, pos + 1);
+            }
+            pos += 1 + address_length;
+        }
+
+        // Segmentation header: repetition count and segment size
+        if pos + 2 > end { return; }
+        let segment_size = read_u16_be(group, pos) & 0x1fff;
+        pos += 2;
+        if pos + segment_size > end { return; }
+        let data = view(group, pos, pos + segment_size);
+
+        match group_type {
+            MOT_HEADER if segment == 0 && data.len() >= 7 => {
+                // The header core: body size, header size, content type and subtype.
+                self.header_transport_id = transport_id;
+                self.header_valid = true;
+                self.content_type = (data[5] as uint >> 1) & 63;
+                self.content_subtype = ((data[5] as uint & 1) << 8) | (data[6] as uint);
+            }
+            MOT_BODY => {
+                if segment == 0 {
+                    self.body_transport_id = transport_id;
+                    self.body_size = 0;
+                    self.body_next_segment = 0;
+                }
+                if transport_id != self.body_transport_id || segment != self.body_next_segment ||
+                        self.body_size + data.len() > self.body.len() {
+                    return;
+                }
+                for data.each |byte| {
+                    self.body[self.body_size] = *byte;
+                    self.body_size += 1;
+                }
+                self.body_next_segment += 1;
+
+                if last && self.header_valid && self.header_transport_id == transport_id {
+                    self.body_next_segment = 0x8000;    // Ignore repetitions.
+                    f(&MotObject(self.content_type, self.content_subtype,
+                                 view(self.body, 0, self.body_size)));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Elementary stream reassembly
+//
+// Containers cut the audio stream into packets without regard to frame boundaries. The assembler
+// collects packet payloads and hands out whole frames, each with the timestamp of the packet it
+// started in.
+
+const ASSEMBLER_SIZE: uint = 8192;      // Several of the largest frames
+const ASSEMBLER_TIMESTAMPS: uint = 16;
+
+struct FrameAssembler {
+    data: [mut u8]/8192;
+    mut len: uint;
+
+    // Pending timestamps and the buffer offsets they apply from, oldest first. A timestamp
+    // applies to the first frame starting at or after its offset.
+    pts_offsets: [mut uint]/16;
+    pts_values: [mut u64]/16;
+    mut pts_count: uint;
+}
+
+fn FrameAssembler() -> FrameAssembler {
+    FrameAssembler {
+        data: [ mut 0, ..8192 ],
+        len: 0,
+        pts_offsets: [ mut 0, ..16 ],
+        pts_values: [ mut 0, ..16 ],
+        pts_count: 0
+    }
+}
+
+impl FrameAssembler {
+    // Adds a packet payload, calling the given function for each frame completed.
+    fn push(payload: &[u8], pts: option<u64>, f: fn(&[u8], option<u64>)) {
+        match pts {
+            Some(pts) => {
+                if self.pts_count == ASSEMBLER_TIMESTAMPS {
+                    self.drop_timestamps(1);
+                }
+                self.pts_offsets[self.pts_count] = self.len;
+                self.pts_values[self.pts_count] = pts;
+                self.pts_count += 1;
+            }
+            None => {}
+        }
+
+        let mut payload = payload;
+        while payload.len() > 0 {
+            let mut count = ASSEMBLER_SIZE - self.len;
+            if count > payload.len() {
+                count = payload.len();
+            }
+            for uint::range(0, count) |i| {
+                self.data[self.len + i] = payload[i];
+            }
+            self.len += count;
+            payload = view(payload, count, payload.len());
+
+            self.drain(false, f);
+            if self.len == ASSEMBLER_SIZE {
+                // Nothing in a full buffer looks like a frame; throw it away.
+                self.consume(self.len);
+            }
+        }
+    }
+
+    // Hands out whatever frames remain at the end of the stream.
+    fn finish(f: fn(&[u8], option<u64>)) {
+        self.drain(true, f);
+        self.consume(self.len);
+    }
+
+    // Discards any partial frame, after data was lost.
+    fn reset() {
+        self.consume(self.len);
+        self.pts_count = 0;
+    }
+
+    fn drain(at_end: bool, f: fn(&[u8], option<u64>)) {
+        loop {
+            // Look for a header followed, unless the stream ends, by another one.
+            let mut pos = 0;
+            let mut frame_size = 0;
+            let mut complete = false;
+            while pos + 4 <= self.len {
+                match parse_header(view(self.data, pos, self.len)) {
+                    OK(header) => {
+                        frame_size = header.frame_size();
+                        let next = pos + frame_size;
+                        if next + 4 > self.len {
+                            complete = at_end && next <= self.len;
+                            break;
+                        }
+                        match parse_header(view(self.data, next, self.len)) {
+                            OK(next_header) if next_header.sampling_frequency ==
+                                    header.sampling_frequency => {
+                                complete = true;
+                                break;
+                            }
+                            _ => {}
+                        }
+                    }
+                    Error(_) => {}
+                }
+                pos += 1;
+            }
+
+            if !complete {
+                // Keep the bytes that may still turn out to be the start of a frame.
+                self.consume(pos);
+                return;
+            }
+
+            // Find the timestamp for this frame.
+            let mut pts = None;
+            let mut used = 0;
+            while used < self.pts_count && self.pts_offsets[used] <= pos {
+                pts = Some(self.pts_values[used]);
+                used += 1;
+            }
+            self.drop_timestamps(used);
+
+            f(view(self.data, pos, pos + frame_size), pts);
+            self.consume(pos + frame_size);
+        }
+    }
+
+    // Removes bytes from the start of the buffer.
+    fn consume(count: uint) {
+        for uint::range(count, self.len) |i| {
+            self.data[i - count] = self.data[i];
+        }
+        self.len -= count;
+        for uint::range(0, self.pts_count) |i| {
+            self.pts_offsets[i] = if self.pts_offsets[i] > count {
+                self.pts_offsets[i] - count
+            } else {
+                0
+            };
+        }
+    }
+
+    fn drop_timestamps(count: uint) {
+        for uint::range(count, self.pts_count) |i| {
+            self.pts_offsets[i - count] = self.pts_offsets[i];
+            self.pts_values[i - count] = self.pts_values[i];
+        }
+        self.pts_count -= count;
+    }
+}
+
+// Program streams
+//
+// An MPEG-1 system stream (ISO 11172-1) or MPEG-2 program stream (ISO 13818-1, as in VOB files)
+// is a sequence of packs, each a pack header, an optional system header and PES packets. Audio
+// streams have the IDs 0xC0 to 0xDF. The two differ in the layout of pack and PES headers.
+
+const PACK_START_CODE: u8 = 0xba;
+const SYSTEM_HEADER_START_CODE: u8 = 0xbb;
+const END_CODE: u8 = 0xb9;
+const AUDIO_STREAM_FIRST: u8 = 0xc0;
+const AUDIO_STREAM_LAST: u8 = 0xdf;
+
+// Returns the 90 kHz timestamp stored in the 5 bytes at the given position, with its marker bits.
+fn read_timestamp(data: &[u8], pos: uint) -> u64 {
+    (((data[pos] as u64) >> 1) & 7) << 30 | (data[pos + 1] as u64) << 22 |
+        ((data[pos + 2] as u64) >> 1) << 15 | (data[pos + 3] as u64) << 7 |
+        (data[pos + 4] as u64) >> 1
+}
+
+fn is_start_code(data: &[u8], pos: uint) -> bool {
+    pos + 4 <= data.len() && data[pos] == 0 && data[pos + 1] == 0 && data[pos + 2] == 1
+}
+
+// Parses the header of an MPEG-1 PES packet whose data (after the length field) is given,
+// returning the offset of the payload and the presentation timestamp.
+fn parse_mpeg1_pes_header(packet: &[u8]) -> option<(uint, option<u64>)> {
+    let mut pos = 0;
+    while pos < packet.len() && packet[pos] == 0xff {
+        pos += 1;   // Stuffing
+    }
+    if pos < packet.len() && (packet[pos] >> 6) == 1 {
+        pos += 2;   // STD buffer size
+    }
+    if pos >= packet.len() {
+        return None;
+    }
+    return match packet[pos] >> 4 {
+        2 if pos + 5 <= packet.len() => Some((pos + 5, Some(read_timestamp(packet, pos)))),
+        3 if pos + 10 <= packet.len() => Some((pos + 10, Some(read_timestamp(packet, pos)))),
+        0 if packet[pos] == 0x0f => Some((pos + 1, None)),
+        _ => None
+    };
+}
+
+// Parses the header of a PES packet, which must start with the packet start code, returning the
+// offset of the payload and the presentation timestamp.
+fn parse_pes_header(packet: &[u8]) -> option<(uint, option<u64>)> {
+    if packet.len() < 9 || !is_start_code(packet, 0) {
+        return None;
+    }
+    if (packet[6] >> 6) != 2 {
+        return match parse_mpeg1_pes_header(view(packet, 6, packet.len())) {
+            Some((start, pts)) => Some((start + 6, pts)),
+            None => None
+        };
+    }
+
+    // MPEG-2 header: two bytes of flags, then the length of the optional fields, which appear
+    // in the order of the flags. Walk them to make sure they fit.
+    let flags = packet[7];
+    let header_end = 9 + (packet[8] as uint);
+    if header_end > packet.len() {
+        return None;
+    }
+    let mut pos = 9;
+    let mut pts = None;
+    match flags >> 6 {
+        0 => {}
+        2 if header_end >= pos + 5 => { pts = Some(read_timestamp(packet, pos)); pos += 5; }
+        3 if header_end >= pos + 10 => { pts = Some(read_timestamp(packet, pos)); pos += 10; }
+        _ => return None    // Truncated, or DTS without PTS, which is forbidden.
+    }
+    if (flags & 0x20) != 0 { pos += 6; }    // ESCR
+    if (flags & 0x10) != 0 { pos += 3; }    // ES rate
+    if (flags & 0x08) != 0 { pos += 1; }    // DSM trick mode
+    if (flags & 0x04) != 0 { pos += 1; }    // Additional copy info
+    if (flags & 0x02) != 0 { pos += 2; }    // Previous PES CRC
+    if (flags & 0x01) != 0 && pos < header_end {
+        // PES extension, with flags of its own.
+        let extension_flags = packet[pos];
+        pos += 1;
+        if (extension_flags & 0x80) != 0 { pos += 16; }     // PES private data
+        if (extension_flags & 0x40) != 0 && pos < header_end {
+            pos += 1 + (packet[pos] as uint);               // Pack header field
+        }
+        if (extension_flags & 0x20) != 0 { pos += 2; }     // Packet sequence counter
+        if (extension_flags & 0x10) != 0 { pos += 2; }     // P-STD buffer
+        if (extension_flags & 0x01) != 0 && pos < header_end {
+            pos += 1 + (packet[pos] as uint & 0x7f);        // PES extension 2
+        }
+    }
+    if pos > header_end {
+        return None;
+    }
+    return Some((header_end, pts));     // The rest is stuffing.
+}
+
+// Returns the size of the pack header at the given position.
+fn pack_header_size(data: &[u8], pos: uint) -> uint {
+    if pos + 14 <= data.len() && (data[pos + 4] >> 6) == 1 {
+        14 + (data[pos + 13] as uint & 7)   // MPEG-2, with stuffing
+    } else {
+        12                                  // MPEG-1
+    }
+}
+
+// Demultiplexes a program stream, calling the given function with the payload and timestamp of
+// each packet of the given audio stream, or of the first audio stream found if none is given.
+fn demux_program_stream(data: &[u8], stream_id: option<u8>, f: fn(&[u8], option<u64>)) {
+    let mut stream_id = stream_id;
+    let mut pos = 0;
+    while pos + 4 <= data.len() {
+        if !is_start_code(data, pos) {
+            pos += 1;   // Lost sync; look for the next start code.
+            loop;
+        }
+
+        let code = data[pos + 3];
+        if code == END_CODE {
+            return;
+        }
+        if code == PACK_START_CODE {
+            pos += pack_header_size(data, pos);
+            loop;
+        }
+        if code < SYSTEM_HEADER_START_CODE || pos + 6 > data.len() {
+            pos += 4;
+            loop;
+        }
+
+        // System header, program stream map or PES packet; all have a length. Private streams
+        // (which carry AC-3 and LPCM on DVDs) and padding are skipped.
+        let end = uint::min(pos + 6 + read_u16_be(data, pos + 4), data.len());
+        if code >= AUDIO_STREAM_FIRST && code <= AUDIO_STREAM_LAST {
+            if stream_id.is_none() {
+                stream_id = Some(code);
+            }
+            if stream_id == Some(code) {
+                let packet = view(data, pos, end);
+                match parse_pes_header(packet) {
+                    Some((start, pts)) => f(view(packet, start, packet.len()), pts),
+                    None => {}
+                }
+            }
+        }
+        pos = end;
+    }
+}
+
+// MPEG-2 transport streams
+//
+// A transport stream is a sequence of 188-byte packets, each carrying part of a PES packet or
+// PSI table on one PID. The PAT on PID 0 lists the PMT of each program, and each PMT lists the
+// program's elementary streams by type. MPEG-1 and MPEG-2 audio have stream types 3 and 4.
+
+const TS_PACKET_SIZE: uint = 188;
+const TS_SYNC_BYTE: u8 = 0x47;
+const PAT_PID: uint = 0;
+const NULL_PID: uint = 0x1fff;
+const STREAM_TYPE_MPEG1_AUDIO: u8 = 3;
+const STREAM_TYPE_MPEG2_AUDIO: u8 = 4;
+const MAX_PROGRAMS: uint = 16;
+
+// Events from a packetized stream
+enum PacketEvent {
+    // The payload of an audio packet, with its presentation timestamp if it has one
+    Payload(&self/[u8], option<u64>),
+    // Data was lost; any partial frame should be discarded.
+    Discontinuity
+}
+
+// Returns the size of the packets in a transport stream: 188 bytes, or 192 or 204 with
+// timecodes or Reed-Solomon parity. Returns None if the data isn't a transport stream.
+fn ts_packet_size(data: &[u8]) -> option<uint> {
+    for [ 188u, 192, 204 ].each |size| {
+        let offset = if *size == 192 { 4 } else { 0 };
+        if data.len() >= offset + *size * 2 + 1 && data[offset] == TS_SYNC_BYTE &&
+                data[offset + *size] == TS_SYNC_BYTE && data[offset + *size * 2] == TS_SYNC_BYTE {
+            return Some(*size);
+        }
+    }
+    return None;
+}
+
+struct TsDemuxer {
+    // The audio PID to play, if chosen, and the PMT PIDs from the PAT
+    mut audio_pid: option<uint>;
+    pmt_pids: [mut uint]/16;
+    mut pmt_count: uint;
+
+    // Continuity counter expected next on the audio PID; 16 before the first packet
+    mut continuity: uint;
+
+    // Whether the current PES packet's payload is being passed on
+    mut in_pes: bool;
+
+    // The PSI section being assembled, which may span several packets: the PID it's on, and
+    // its bytes so far
+    mut section_pid: option<uint>;
+    section: [mut u8]/1024;     // Longest PAT or PMT section
+    mut section_len: uint;
+}
+
+fn TsDemuxer(audio_pid: option<uint>) -> TsDemuxer {
+    TsDemuxer {
+        audio_pid: audio_pid,
+        pmt_pids: [ mut 0, ..16 ],
+        pmt_count: 0,
+        continuity: 16,
+        in_pes: false,
+        section_pid: None,
+        section: [ mut 0, ..1024 ],
+        section_len: 0
+    }
+}
+
+impl TsDemuxer {
+    // Demultiplexes a transport stream, calling the given function for each piece of audio
+    // payload and each discontinuity.
+    fn demux(data: &[u8], packet_size: uint, f: fn(&PacketEvent)) {
+        let offset = if packet_size == 192 { 4 } else { 0 };
+        let mut pos = offset;
+        while pos + TS_PACKET_SIZE <= data.len() {
+            if data[pos] != TS_SYNC_BYTE {
+                // Lost sync; look for the next sync byte and signal the loss.
+                pos += 1;
+                while pos + TS_PACKET_SIZE <= data.len() && data[pos] != TS_SYNC_BYTE {
+                    pos += 1;
+                }
+                self.lost(f);
+                loop;
+            }
+            self.packet(view(data, pos, pos + TS_PACKET_SIZE), f);
+            pos += packet_size;
+        }
+    }
+
+    fn packet(packet: &[u8], f: fn(&PacketEvent)) {
+        let transport_error = (packet[1] & 0x80) != 0;
+        let unit_start = (packet[1] & 0x40) != 0;
+        let pid = ((packet[1] as uint & 0x1f) << 8) | (packet[2] as uint);
+        let adaptation_field_control = (packet[3] >> 4) & 3;
+        let continuity = packet[3] as uint & 15;
+        if pid == NULL_PID {
+            return;
+        }
+
+        // Skip the adaptation field, noting whether it announces a discontinuity.
+        let mut start = 4;
+        let mut discontinuity_indicator = false;
+        if (adaptation_field_control & 2) != 0 {
+            let length = packet[4] as uint;
+            discontinuity_indicator = length > 0 && (packet[5] & 0x80) != 0;
+            start = 5 + length;
+        }
+        let has_payload = (adaptation_field_control & 1) != 0 && start < TS_PACKET_SIZE;
+        let payload = view(packet, uint::min(start, TS_PACKET_SIZE), TS_PACKET_SIZE);
+
+        if self.audio_pid != Some(pid) {
+            if has_payload && !transport_error && (pid == PAT_PID ||
+                    vec::contains(view(self.pmt_pids, 0, self.pmt_count), &pid)) {
+                self.psi_packet(pid, payload, unit_start);
+            }
+            return;
+        }
+
+        if transport_error {
+            self.lost(f);
+            return;
+        }
+
+        // Check the continuity counter. It only advances on packets with payload, and a packet
+        // may be sent twice.
+        if has_payload {
+            let expected = self.continuity;
+            self.continuity = (continuity + 1) & 15;
+            if expected != 16 && continuity != expected && !discontinuity_indicator {
+                if ((continuity + 1) & 15) == expected {
+                    return;     // Duplicate packet
+                }
+                self.lost(f);
+                self.continuity = (continuity + 1) & 15;
+            }
+        } else {
+            return;
+        }
+
+        if unit_start {
+            match parse_pes_header(payload) {
+                Some((header_end, pts)) => {
+                    self.in_pes = true;
+                    f(&Payload(view(payload, header_end, payload.len()), pts));
+                }
+                None => self.in_pes = false
+            }
+        } else if self.in_pes {
+            f(&Payload(payload, None));
+        }
+    }
+
+    fn lost(f: fn(&PacketEvent)) {
+        if self.in_pes {
+            f(&Discontinuity);
+        }
+        self.in_pes = false;
+        self.continuity = 16;
+    }
+
+    // Adds the payload of a packet on the PAT or a PMT PID to the section being assembled, and
+    // reads the section once it's complete. In a packet starting a unit, the pointer field gives
+    // the start of a new section; the bytes before it end the previous one.
+    fn psi_packet(pid: uint, payload: &[u8], unit_start: bool) {
+        let mut data = payload;
+        if unit_start {
+            if payload.len() == 0 {
+                return;
+            }
+            let start = 1 + (payload[0] as uint);
+            if start > payload.len() {
+                self.section_pid = None;
+                return;
+            }
+            if self.section_pid == Some(pid) {
+                self.add_section_data(view(payload, 1, start));
+            }
+            self.section_pid = Some(pid);
+            self.section_len = 0;
+            data = view(payload, start, payload.len());
+        } else if self.section_pid != Some(pid) {
+            return;
+        }
+        self.add_section_data(data);
+    }
+
+    fn add_section_data(data: &[u8]) {
+        let pid = match self.section_pid {
+            Some(pid) => pid,
+            None => return
+        };
+        let count = uint::min(data.len(), 1024 - self.section_len);
+        for uint::range(0, count) |i| {
+            self.section[self.section_len + i] = data[i];
+        }
+        self.section_len += count;
+        if self.section_len < 3 {
+            return;
+        }
+        let end = 3 + (read_u16_be(view(self.section, 0, self.section_len), 1) & 0xfff);
+        if self.section_len < end {
+            if self.section_len == 1024 {
+                self.section_pid = None;    // Too long to be a PAT or PMT
+            }
+            return;
+        }
+
+        // The section is complete. Drop its CRC.
+        self.section_pid = None;
+        if end < 12 {
+            return;
+        }
+        let section = view(self.section, 0, end - 4);
+        if pid == PAT_PID {
+            self.read_pat(section);
+        } else {
+            self.read_pmt(section);
+        }
+    }
+
+    fn read_pat(section: &[u8]) {
+        if section[0] != 0 {
+            return;
+        }
+        self.pmt_count = 0;
+        let mut pos = 8;
+        while pos + 4 <= section.len() && self.pmt_count < MAX_PROGRAMS {
+            let program_number = read_u16_be(section, pos);
+            if program_number != 0 {
+                self.pmt_pids[self.pmt_count] = read_u16_be(section, pos + 2) & 0x1fff;
+                self.pmt_count += 1;
+            }
+            pos += 4;
+        }
+    }
+
+    fn read_pmt(section: &[u8]) {
+        if section[0] != 2 || section.len() < 12 {
+            return;
+        }
+        let mut pos = 12 + (read_u16_be(section, 10) & 0xfff);
+        while pos + 5 <= section.len() {
+            let stream_type = section[pos];
+            let pid = read_u16_be(section, pos + 1) & 0x1fff;
+            if self.audio_pid.is_none() && (stream_type == STREAM_TYPE_MPEG1_AUDIO ||
+                                            stream_type == STREAM_TYPE_MPEG2_AUDIO) {
+                self.audio_pid = Some(pid);
+                return;
+            }
+            pos += 5 + (read_u16_be(section, pos + 3) & 0xfff);
+        }
+    }
+}
+
+// Matroska
+//
+// Matroska (and WebM) files are trees of EBML elements, each an ID and a size, both stored as
+// variable-length integers. Audio frames are stored in SimpleBlocks or BlockGroups within
+// clusters, possibly several to a block ("lacing").
+
+const EBML_HEADER_ID: uint = 0x1a45dfa3;
+const SEGMENT_ID: uint = 0x18538067;
+const INFO_ID: uint = 0x1549a966;
+const TIMECODE_SCALE_ID: uint = 0x2ad7b1;
+const TRACKS_ID: uint = 0x1654ae6b;
+const TRACK_ENTRY_ID: uint = 0xae;
+const TRACK_NUMBER_ID: uint = 0xd7;
+const CODEC_ID_ID: uint = 0x86;
+const CLUSTER_ID: uint = 0x1f43b675;
+const CLUSTER_TIMECODE_ID: uint = 0xe7;
+const SIMPLE_BLOCK_ID: uint = 0xa3;
+const BLOCK_GROUP_ID: uint = 0xa0;
+const BLOCK_ID: uint = 0xa1;
+
+const MATROSKA_CODEC_MP2: &static/str = "A_MPEG/L2";
+
+// Reads the variable-length integer at the given position, returning its value and length. IDs
+// keep their length marker bit; sizes don't. A size with all bits set means "unknown", which is
+// returned as uint::max_value.
+fn read_vint(data: &[u8], pos: uint, is_id: bool) -> option<(uint, uint)> {
+    if pos >= data.len() || data[pos] == 0 {
+        return None;
+    }
+    let mut length = 1;
+    while (data[pos] & (0x80 >> (length - 1))) == 0 {
+        length += 1;
+    }
+    if pos + length > data.len() {
+        return None;
+    }
+    let mut value = if is_id { data[pos] as uint } else { data[pos] as uint & (0xff >> length) };
+    let mut all_ones = value == (0xff >> length);
+    for uint::range(1, length) |i| {
+        value = (value << 8) | (data[pos + i] as uint);
+        all_ones = all_ones && data[pos + i] == 0xff;
+    }
+    if !is_id && all_ones {
+        value = uint::max_value;
+    }
+    return Some((value, length));
+}
+
+fn read_uint(data: &[u8]) -> u64 {
+    let mut value = 0;
+    for data.each |byte| {
+        value = (value << 8) | (*byte as u64);
+    }
+    return value;
+}
+
+struct MatroskaDemuxer {
+    // The Layer II track, once found
+    mut track: option<uint>;
+
+    // The track entry being read
+    mut entry_number: uint;
+    mut entry_is_mp2: bool;
+
+    // Nanoseconds per timecode unit, and the timecode of the current cluster
+    mut timecode_scale: u64;
+    mut cluster_timecode: u64;
+
+    // Sizes of the frames in a laced block
+    lace_sizes: [mut uint]/256;
+}
+
+fn MatroskaDemuxer() -> MatroskaDemuxer {
+    MatroskaDemuxer {
+        track: None,
+        entry_number: 0,
+        entry_is_mp2: false,
+        timecode_scale: 1000000,
+        cluster_timecode: 0,
+        lace_sizes: [ mut 0, ..256 ]
+    }
+}
+
+impl MatroskaDemuxer {
+    // Demultiplexes a Matroska file, calling the given function with each frame of the Layer II
+    // track and its timestamp in 90 kHz units.
+    fn demux(data: &[u8], f: fn(&[u8], option<u64>)) -> MP2Result<()> {
+        self.walk(data, f);
+        if self.track.is_none() {
+            return Error("no A_MPEG/L2 track found");
+        }
+        return OK(());
+    }
+
+    // Handles the elements in the given data.
+    fn walk(data: &[u8], f: fn(&[u8], option<u64>)) {
+        let mut pos = 0;
+        while pos < data.len() {
+            let (id, id_length) = match read_vint(data, pos, true) {
+                Some(result) => result,
+                None => return
+            };
+            let (size, size_length) = match read_vint(data, pos + id_length, false) {
+                Some(result) => result,
+                None => return
+            };
+            let start = pos + id_length + size_length;
+
+            // A cluster of unknown size (as written when streaming) ends where the next cluster
+            // starts. Its children are read as siblings of it, so that a run of such clusters is
+            // handled here rather than nesting one level deeper per cluster.
+            if id == CLUSTER_ID && size == uint::max_value {
+                self.cluster_timecode = 0;
+                pos = start;
+                loop;
+            }
+
+            // Other elements of unknown size, such as a streamed segment, extend to the end of
+            // their parent.
+            let end = if size == uint::max_value || size > data.len() - start {
+                data.len()
+            } else {
+                start + size
+            };
+            let body = view(data, start, end);
+            pos = end;
+
+            if id == SEGMENT_ID || id == INFO_ID || id == TRACKS_ID || id == BLOCK_GROUP_ID {
+                self.walk(body, f);
+            } else if id == CLUSTER_ID {
+                self.cluster_timecode = 0;
+                self.walk(body, f);
+            } else if id == TRACK_ENTRY_ID {
+                self.entry_number = 0;
+                self.entry_is_mp2 = false;
+                self.walk(body, f);
+                if self.entry_is_mp2 && self.track.is_none() {
+                    self.track = Some(self.entry_number);
+                }
+            } else if id == TIMECODE_SCALE_ID {
+                self.timecode_scale = read_uint(body);
+            } else if id == TRACK_NUMBER_ID {
+                self.entry_number = read_uint(body) as uint;
+            } else if id == CODEC_ID_ID {
+                self.entry_is_mp2 = body.len() >= MATROSKA_CODEC_MP2.len() &&
+                    has_magic(body, 0, MATROSKA_CODEC_MP2);
+            } else if id == CLUSTER_TIMECODE_ID {
+                self.cluster_timecode = read_uint(body);
+            } else if id == SIMPLE_BLOCK_ID || id == BLOCK_ID {
+                self.block(body, f);
+            }
+        }
+    }
+
+    fn block(block: &[u8], f: fn(&[u8], option<u64>)) {
+        let (track, track_length) = match read_vint(block, 0, false) {
+            Some(result) => result,
+            None => return
+        };
+        if self.track != Some(track) || block.len() < track_length + 3 {
+            return;
+        }
+
+        // Signed 16-bit timecode relative to the cluster.
+        let relative = (read_u16_be(block, track_length) as i16) as i64;
+        let timecode = (self.cluster_timecode as i64) + relative;
+        let pts = if timecode >= 0 {
+            Some((timecode as u64) * self.timecode_scale * 9 / 100000)
+        } else {
+            None
+        };
+
+        let flags = block[track_length + 2];
+        let mut pos = track_length + 3;
+        let lacing = (flags >> 1) & 3;
+        if lacing == 0 {
+            f(view(block, pos, block.len()), pts);
+            return;
+        }
+
+        // Laced block: a frame count, then the sizes of all frames but the last.
+        if pos >= block.len() {
+            return;
+        }
+        let count = (block[pos] as uint) + 1;
+        pos += 1;
+        match lacing {
+            1 => {
+                // Xiph lacing: sizes as runs of 255s.
+                for uint::range(0, count - 1) |i| {
+                    let mut size = 0;
+                    while pos < block.len() && block[pos] == 255 {
+                        size += 255;
+                        pos += 1;
+                    }
+                    if pos >= block.len() {
+                        return;
+                    }
+                    size += block[pos] as uint;
+                    pos += 1;
+                    self.lace_sizes[i] = size;
+                }
+            }
+            3 => {
+                // EBML lacing: the first size, then signed differences.
+                let mut size = 0;
+                for uint::range(0, count - 1) |i| {
+                    let (value, length) = match read_vint(block, pos, false) {
+                        Some(result) => result,
+                        None => return
+                    };
+                    if i == 0 {
+                        size = value as int;
+                    } else {
+                        size += (value as int) - ((1 << (7 * length - 1)) - 1);
+                    }
+                    if size < 0 {
+                        return;
+                    }
+                    self.lace_sizes[i] = size as uint;
+                    pos += length;
+                }
+            }
+            _ => {
+                // Fixed-size lacing
+                for uint::range(0, count - 1) |i| {
+                    self.lace_sizes[i] = (block.len() - pos) / count;
+                }
+            }
+        }
+
+        // Only the first frame carries the block's timestamp; the rest follow on.
+        for uint::range(0, count) |i| {
+            let size = if i < count - 1 { self.lace_sizes[i] } else { block.len() - pos };
+            if pos + size > block.len() {
+                return;
+            }
+            f(view(block, pos, pos + size), if i == 0 { pts } else { None });
+            pos += size;
+        }
+    }
+}
+
+// MP4
+//
+// ISO base media files (MP4, MOV) are trees of boxes. Each track's sample table (stbl) gives the
+// size of every sample, how samples are grouped into chunks, where each chunk is in the file and
+// how long each sample lasts. MPEG audio tracks use an "mp4a" sample entry whose ES descriptor
+// gives the object type: 0x6B for MPEG-1 audio and 0x69 for MPEG-2 audio.
+
+const OBJECT_TYPE_MPEG2_AUDIO: u8 = 0x69;
+const OBJECT_TYPE_MPEG1_AUDIO: u8 = 0x6b;
+
+// Returns the type of the box at the given position and the bounds of its contents.
+fn read_box(data: &[u8], pos: uint) -> option<(uint, uint, uint)> {
+    if pos + 8 > data.len() {
+        return None;
+    }
+    let box_type = read_u32_be(data, pos + 4);
+    let (start, size) = match read_u32_be(data, pos) {
+        0 => (pos + 8, data.len() - pos),   // Extends to the end of the file
+        1 => {
+            if pos + 16 > data.len() {
+                return None;
+            }
+            let size = (read_u32_be(data, pos + 8) << 32) | read_u32_be(data, pos + 12);
+            (pos + 16, size)
+        }
+        size => (pos + 8, size)
+    };
+    if size < start - pos || size > data.len() - pos {
+        return None;
+    }
+    return Some((box_type, start, pos + size));
+}
+
+// Returns a box type as a number, from its four-character code.
+fn fourcc(code: String) -> uint {
+    ((code[0] as uint) << 24) | ((code[1] as uint) << 16) | ((code[2] as uint) << 8) |
+        (code[3] as uint)
+}
+
+// Reads the length of an MPEG-4 descriptor: up to four bytes of 7 bits each.
+fn read_descriptor_length(data: &[u8], pos: &mut uint) -> uint {
+    let mut length = 0;
+    for 4.times {
+        if *pos >= data.len() {
+            break;
+        }
+        let byte = data[*pos];
+        *pos += 1;
+        length = (length << 7) | (byte as uint & 0x7f);
+        if (byte & 0x80) == 0 {
+            break;
+        }
+    }
+    return length;
+}
+
+// The sample table of an MPEG audio track. The tables are views into the file, with the full box
+// headers stripped.
+struct Mp4Track {
+    mut is_sound: bool;
+    mut is_mpeg_audio: bool;
+    mut timescale: uint;
+    mut stsz: &self/[u8];
+    mut stsc: &self/[u8];
+    mut chunk_offsets: &self/[u8];
+    mut chunk_offsets_64: bool;
+    mut stts: &self/[u8];
+}
+
+fn Mp4Track(data: &a/[u8]) -> Mp4Track/&a {
+    let empty = view(data, 0, 0);
+    Mp4Track {
+        is_sound: false,
+        is_mpeg_audio: false,
+        timescale: 0,
+        stsz: empty,
+        stsc: empty,
+        chunk_offsets: empty,
+        chunk_offsets_64: false,
+        stts: empty
+    }
+}
+
+impl Mp4Track {
+    // Reads the boxes of a track.
+    fn read(data: &self/[u8]) {
+        let mut pos = 0;
+        loop {
+            let (box_type, start, end) = match read_box(data, pos) {
+                Some(result) => result,
+                None => return
+            };
+            let body = view(data, start, end);
+            pos = end;
+
+            if box_type == fourcc("mdia") || box_type == fourcc("minf") ||
+                    box_type == fourcc("stbl") {
+                self.read(body);
+            } else if box_type == fourcc("mdhd") && body.len() >= 24 {
+                self.timescale = if body[0] == 1 { read_u32_be(body, 20) } else {
+                    read_u32_be(body, 12)
+                };
+            } else if box_type == fourcc("hdlr") && body.len() >= 12 {
+                self.is_sound = read_u32_be(body, 8) == fourcc("soun");
+            } else if box_type == fourcc("stsd") && body.len() >= 8 {
+                self.read_sample_entry(view(body, 8, body.len()));
+            } else if body.len() >= 4 {
+                let table = view(body, 4, body.len());
+                if box_type == fourcc("stsz") {
+                    self.stsz = table;
+                } else if box_type == fourcc("stsc") {
+                    self.stsc = table;
+                } else if box_type == fourcc("stco") || box_type == fourcc("co64") {
+                    self.chunk_offsets = table;
+                    self.chunk_offsets_64 = box_type == fourcc("co64");
+                } else if box_type == fourcc("stts") {
+                    self.stts = table;
+                }
+            }
+        }
+    }
+
+    fn read_sample_entry(data: &[u8]) {
+        let (entry_type, start, end) = match read_box(data, 0) {
+            Some(result) => result,
+            None => return
+        };
+        if entry_type == fourcc(".mp3") {
+            self.is_mpeg_audio = true;
+            return;
+        }
+        if entry_type != fourcc("mp4a") || start + 28 > end {
+            return;
+        }
+
+        // Skip the audio sample entry fields to find the ES descriptor box.
+        let entry = view(data, start + 28, end);
+        let mut pos = 0;
+        loop {
+            let (box_type, start, end) = match read_box(entry, pos) {
+                Some(result) => result,
+                None => return
+            };
+            pos = end;
+            if box_type != fourcc("esds") || start + 4 > end {
+                loop;
+            }
+
+            // ES descriptor (tag 3), containing the decoder config descriptor (tag 4).
+            let esds = view(entry, start + 4, end);
+            let mut pos = 0;
+            if esds.len() < 1 || esds[0] != 3 {
+                return;
+            }
+            pos += 1;
+            read_descriptor_length(esds, &mut pos);
+            if pos + 3 > esds.len() {
+                return;
+            }
+            let flags = esds[pos + 2];
+            pos += 3;
+            if (flags & 0x80) != 0 { pos += 2; }                        // Depends on ES ID
+            if (flags & 0x40) != 0 && pos < esds.len() {                // URL
+                pos += 1 + (esds[pos] as uint);
+            }
+            if (flags & 0x20) != 0 { pos += 2; }                        // OCR ES ID
+            if pos >= esds.len() || esds[pos] != 4 {
+                return;
+            }
+            pos += 1;
+            read_descriptor_length(esds, &mut pos);
+            if pos < esds.len() {
+                let object_type = esds[pos];
+                self.is_mpeg_audio = object_type == OBJECT_TYPE_MPEG1_AUDIO ||
+                    object_type == OBJECT_TYPE_MPEG2_AUDIO;
+            }
+            return;
+        }
+    }
+
+    fn is_usable() -> bool {
+        self.is_sound && self.is_mpeg_audio && self.stsz.len() >= 8 && self.stsc.len() >= 4 &&
+            self.chunk_offsets.len() >= 4
+    }
+
+    // Calls the given function with each sample and its timestamp in 90 kHz units.
+    fn samples(data: &[u8], f: fn(&[u8], option<u64>)) {
+        let fixed_size = read_u32_be(self.stsz, 0);
+        let sample_count = read_u32_be(self.stsz, 4);
+        let stsc_count = read_u32_be(self.stsc, 0);
+        let chunk_count = read_u32_be(self.chunk_offsets, 0);
+        let stts_count = if self.stts.len() >= 4 { read_u32_be(self.stts, 0) } else { 0 };
+
+        let mut sample = 0;
+        let mut stsc_index = 0;
+        let mut stts_index = 0;
+        let mut stts_left = 0;
+        let mut delta = 0;
+        let mut time: u64 = 0;
+        for uint::range(1, chunk_count + 1) |chunk| {
+            // Find the sample-to-chunk entry for this chunk. Entries give the first chunk they
+            // apply to, counting from 1.
+            while stsc_index + 1 < stsc_count && 4 + (stsc_index + 2) * 12 <= self.stsc.len() &&
+                    read_u32_be(self.stsc, 4 + (stsc_index + 1) * 12) <= chunk {
+                stsc_index += 1;
+            }
+            if 4 + (stsc_index + 1) * 12 > self.stsc.len() {
+                return;
+            }
+            let samples_per_chunk = read_u32_be(self.stsc, 4 + stsc_index * 12 + 4);
+
+            let mut offset = if self.chunk_offsets_64 {
+                if 4 + chunk * 8 > self.chunk_offsets.len() { return; }
+                (read_u32_be(self.chunk_offsets, 4 + (chunk - 1) * 8) << 32) |
+                    read_u32_be(self.chunk_offsets, 8 + (chunk - 1) * 8)
+            } else {
+                if 4 + chunk * 4 > self.chunk_offsets.len() { return; }
+                read_u32_be(self.chunk_offsets, 4 + (chunk - 1) * 4)
+            };
+
+            for samples_per_chunk.times {
+                if sample >= sample_count {
+                    return;
+                }
+                let size = if fixed_size != 0 {
+                    fixed_size
+                } else if 12 + sample * 4 <= self.stsz.len() {
+                    read_u32_be(self.stsz, 8 + sample * 4)
+                } else {
+                    return;
+                };
+                if offset > data.len() || size > data.len() - offset {
+                    return;
+                }
+
+                // Advance through the time-to-sample table.
+                let pts = if stts_count > 0 && self.timescale > 0 {
+                    Some(time * 90000 / (self.timescale as u64))
+                } else {
+                    None
+                };
+                if stts_left == 0 && stts_index < stts_count &&
+                        4 + (stts_index + 1) * 8 <= self.stts.len() {
+                    stts_left = read_u32_be(self.stts, 4 + stts_index * 8);
+                    delta = read_u32_be(self.stts, 8 + stts_index * 8);
+                    stts_index += 1;
+                }
+                if stts_left > 0 {
+                    stts_left -= 1;
+                }
+                time += delta as u64;
+
+                f(view(data, offset, offset + size), pts);
+                offset += size;
+                sample += 1;
+            }
+        }
+    }
+}
+
+// Demultiplexes an MP4 file, calling the given function with each sample of the first MPEG audio
+// track and its timestamp in 90 kHz units.
+fn demux_mp4(data: &[u8], f: fn(&[u8], option<u64>)) -> MP2Result<()> {
+    // Find the movie box.
+    let mut pos = 0;
+    let mut moov = None;
+    loop {
+        match read_box(data, pos) {
+            Some((box_type, start, end)) => {
+                if box_type == fourcc("moov") {
+                    moov = Some(view(data, start, end));
+                    break;
+                }
+                pos = end;
+            }
+            None => break
+        }
+    }
+    let moov = match moov {
+        Some(moov) => moov,
+        None => return Error("no movie box found")
+    };
+
+    // Look for an MPEG audio track.
+    let mut pos = 0;
+    loop {
+        let (box_type, start, end) = match read_box(moov, pos) {
+            Some(result) => result,
+            None => return Error("no MPEG audio track found")
+        };
+        pos = end;
+        if box_type == fourcc("trak") {
+            let track = Mp4Track(data);
+            track.read(view(moov, start, end));
+            if track.is_usable() {
+                track.samples(data, f);
+                return OK(());
+            }
+        }
+    }
+}
+
+// RTP
+//
+// RTP carries MPEG audio in one of two payload formats. RFC 2250 (payload type 14) prefixes each
+// packet with a 4-byte header giving the offset of its data within the frame, so frames may be
+// split across packets or several packed into one. RFC 3119 ("mpa-robust", a dynamic payload
+// type) prefixes each ADU with a descriptor giving its size; for Layer II an ADU is a whole frame.
+// Packets may arrive out of order, so a few are held back and released by sequence number.
+//
+// RFC 3119 interleaving is only defined for Layer III and isn't supported.
+
+const RTP_VERSION: u8 = 2;
+const RTP_PAYLOAD_TYPE_MPA: u8 = 14;
+const RTP_PAYLOAD_TYPE_DYNAMIC: u8 = 96;
+const RTP_MAX_PACKET: uint = 2048;
+const RTP_REORDER_DEPTH: uint = 16;
+
+// Parses an RTP packet header, returning the payload type, sequence number, timestamp and the
+// bounds of the payload.
+fn parse_rtp_header(packet: &[u8]) -> option<(u8, uint, u32, uint, uint)> {
+    if packet.len() < 12 || (packet[0] >> 6) != RTP_VERSION {
+        return None;
+    }
+    let mut start = 12 + (packet[0] as uint & 15) * 4;
+    let mut end = packet.len();
+    if (packet[0] & 0x10) != 0 {
+        // Header extension
+        if start + 4 > end {
+            return None;
+        }
+        start += 4 + read_u16_be(packet, start + 2) * 4;
+    }
+    if (packet[0] & 0x20) != 0 {
+        // Padding; the last byte gives its size.
+        let padding = packet[end - 1] as uint;
+        if padding > end {
+            return None;
+        }
+        end -= padding;
+    }
+    if start > end {
+        return None;
+    }
+    let payload_type = packet[1] & 0x7f;
+    let sequence = read_u16_be(packet, 2);
+    let timestamp = read_u32_be(packet, 4) as u32;
+    return Some((payload_type, sequence, timestamp, start, end));
+}
+
+struct RtpDepacketizer {
+    // Whether the payload format is RFC 3119 rather than RFC 2250
+    robust: bool;
+
+    // Packets held for reordering, by sequence number modulo RTP_REORDER_DEPTH
+    packets: [[mut u8]/2048]/16;
+    packet_sizes: [mut uint]/16;
+    packet_sequences: [mut uint]/16;
+    packet_present: [mut bool]/16;
+
+    // Sequence number of the next packet to release, if any packet has been seen
+    mut next_sequence: option<uint>;
+
+    // Whether packets have been lost since the last one released
+    mut lost: bool;
+}
+
+fn RtpDepacketizer(robust: bool) -> RtpDepacketizer {
+    RtpDepacketizer {
+        robust: robust,
+        packets: [ [ mut 0, ..2048 ], ..16 ],
+        packet_sizes: [ mut 0, ..16 ],
+        packet_sequences: [ mut 0, ..16 ],
+        packet_present: [ mut false, ..16 ],
+        next_sequence: None,
+        lost: false
+    }
+}
+
+impl RtpDepacketizer {
+    // Adds an RTP packet, calling the given function for each piece of audio payload released
+    // and each loss.
+    fn push(packet: &[u8], f: fn(&PacketEvent)) {
+        let (payload_type, sequence) = match parse_rtp_header(packet) {
+            Some((payload_type, sequence, _, _, _)) => (payload_type, sequence),
+            None => return
+        };
+        let expected_type = if self.robust {
+            payload_type >= RTP_PAYLOAD_TYPE_DYNAMIC
+        } else {
+            payload_type == RTP_PAYLOAD_TYPE_MPA
+        };
+        if !expected_type || packet.len() > RTP_MAX_PACKET {
+            return;
+        }
+
+        let next = match self.next_sequence {
+            Some(next) => next,
+            None => { self.next_sequence = Some(sequence); sequence }
+        };
+        let distance = (sequence - next) & 0xffff;
+        if distance >= 0x8000 {
+            return;     // Late or duplicate packet
+        }
+
+        // Make room by giving up on packets that haven't arrived.
+        if distance >= RTP_REORDER_DEPTH {
+            for (distance - RTP_REORDER_DEPTH + 1).times {
+                self.release_one(f);
+            }
+        }
+
+        let slot = sequence % RTP_REORDER_DEPTH;
+        for uint::range(0, packet.len()) |i| {
+            self.packets[slot][i] = packet[i];
+        }
+        self.packet_sizes[slot] = packet.len();
+        self.packet_sequences[slot] = sequence;
+        self.packet_present[slot] = true;
+
+        // Release the packets that are now in order.
+        loop {
+            let next = self.next_sequence.get();
+            let slot = next % RTP_REORDER_DEPTH;
+            if !self.packet_present[slot] || self.packet_sequences[slot] != next {
+                break;
+            }
+            self.release_one(f);
+        }
+    }
+
+    // Releases all held packets, in order.
+    fn finish(f: fn(&PacketEvent)) {
+        for RTP_REORDER_DEPTH.times {
+            self.release_one(f);
+        }
+        self.next_sequence = None;
+    }
+
+    // Releases the next packet in sequence, or notes its loss if it hasn't arrived.
+    fn release_one(f: fn(&PacketEvent)) {
+        let next = match self.next_sequence {
+            Some(next) => next,
+            None => return
+        };
+        self.next_sequence = Some((next + 1) & 0xffff);
+
+        let slot = next % RTP_REORDER_DEPTH;
+        if !self.packet_present[slot] || self.packet_sequences[slot] != next {
+            self.lost = true;
+            return;
+        }
+        self.packet_present[slot] = false;
+        if self.lost {
+            f(&Discontinuity);
+            self.lost = false;
+        }
+
+        let packet = view(self.packets[slot], 0, self.packet_sizes[slot]);
+        let (timestamp, start, end) = match parse_rtp_header(packet) {
+            Some((_, _, timestamp, start, end)) => (timestamp, start, end),
+            None => return
+        };
+        let payload = view(packet, start, end);
+        if self.robust {
+            self.release_adus(payload, timestamp, f);
+        } else if payload.len() >= 4 {
+            // A fragment continuing a frame carries no timestamp of its own.
+            let fragment_offset = read_u16_be(payload, 2);
+            let pts = if fragment_offset == 0 { Some(timestamp as u64) } else { None };
+            f(&Payload(view(payload, 4, payload.len()), pts));
+        }
+    }
+
+    // Releases the ADUs in an RFC 3119 payload. Each descriptor has a continuation bit, a bit
+    // choosing a 6-bit or 14-bit size, and the size.
+    fn release_adus(payload: &[u8], timestamp: u32, f: fn(&PacketEvent)) {
+        let mut pos = 0;
+        let mut pts = Some(timestamp as u64);
+        while pos < payload.len() {
+            let continuation = (payload[pos] & 0x80) != 0;
+            let mut size = payload[pos] as uint & 0x3f;
+            if (payload[pos] & 0x40) != 0 {
+                if pos + 1 >= payload.len() {
+                    return;
+                }
+                size = (size << 8) | (payload[pos + 1] as uint);
+                pos += 1;
+            }
+            pos += 1;
+            let end = uint::min(pos + size, payload.len());
+            f(&Payload(view(payload, pos, end), if continuation { None } else { pts }));
+            pts = None;
+            pos = end;
+        }
+    }
+}
+
+// Packet captures
+//
+// A capture file (libpcap format) is a global header followed by records, each a header and the
+// captured bytes of one link-layer frame. The byte order of the headers is given by the magic
+// number.
+
+const PCAP_MAGIC: uint = 0xa1b2c3d4;
+const PCAP_MAGIC_NANOSECONDS: uint = 0xa1b23c4d;
+const LINKTYPE_NULL: uint = 0;
+const LINKTYPE_ETHERNET: uint = 1;
+const LINKTYPE_RAW: uint = 101;
+const LINKTYPE_LINUX_SLL: uint = 113;
+const ETHERTYPE_IPV4: uint = 0x0800;
+const ETHERTYPE_IPV6: uint = 0x86dd;
+const ETHERTYPE_VLAN: uint = 0x8100;
+const IP_PROTOCOL_UDP: u8 = 17;
+
+// Returns whether the given data is a capture file, and if so whether its headers are
+// big-endian.
+fn pcap_byte_order(data: &[u8]) -> option<bool> {
+    if data.len() < 24 {
+        return None;
+    }
+    let magic = read_u32_be(data, 0);
+    if magic == PCAP_MAGIC || magic == PCAP_MAGIC_NANOSECONDS {
+        return Some(true);
+    }
+    let magic = read_u32_le(data, 0);
+    if magic == PCAP_MAGIC || magic == PCAP_MAGIC_NANOSECONDS {
+        return Some(false);
+    }
+    return None;
+}
+
+// Returns the payload of a UDP datagram in the given IP packet, if it's sent to the given port
+// (or any port if none is given). Fragmented datagrams are ignored.
+fn udp_payload(packet: &a/[u8], port: option<uint>) -> option<&a/[u8]> {
+    if packet.len() < 1 {
+        return None;
+    }
+    let start = match packet[0] >> 4 {
+        4 => {
+            let header_size = (packet[0] as uint & 15) * 4;
+            if packet.len() < 20 || header_size < 20 || packet[9] != IP_PROTOCOL_UDP ||
+                    (read_u16_be(packet, 6) & 0x3fff) != 0 {
+                return None;
+            }
+            header_size
+        }
+        6 => {
+            if packet.len() < 40 || packet[6] != IP_PROTOCOL_UDP {
+                return None;
+            }
+            40
+        }
+        _ => return None
+    };
+    if start + 8 > packet.len() {
+        return None;
+    }
+    if port.is_some() && port != Some(read_u16_be(packet, start + 2)) {
+        return None;
+    }
+    let end = uint::min(start + read_u16_be(packet, start + 4), packet.len());
+    if end < start + 8 {
+        return None;
+    }
+    return Some(view(packet, start + 8, end));
+}
+
+// Calls the given function with the payload of each UDP datagram in a capture file, sent to the
+// given port if one is given.
+fn read_pcap(data: &[u8], port: option<uint>, f: fn(&[u8])) -> MP2Result<()> {
+    let big_endian = match pcap_byte_order(data) {
+        Some(big_endian) => big_endian,
+        None => return Error("not a capture file")
+    };
+    let read_u32 = |pos: uint| if big_endian { read_u32_be(data, pos) } else {
+        read_u32_le(data, pos)
+    };
+    let link_type = read_u32(20);
+    if link_type != LINKTYPE_NULL && link_type != LINKTYPE_ETHERNET && link_type != LINKTYPE_RAW &&
+            link_type != LINKTYPE_LINUX_SLL {
+        return Error(fmt!("unsupported link type %u", link_type));
+    }
+
+    let mut pos = 24;
+    while pos + 16 <= data.len() {
+        let size = read_u32(pos + 8);
+        let start = pos + 16;
+        if size > data.len() - start {
+            break;
+        }
+        let frame = view(data, start, start + size);
+        pos = start + size;
+
+        // Find the IP packet in the link-layer frame.
+        let ip_start = match link_type {
+            LINKTYPE_ETHERNET => {
+                let mut ethertype_pos = 12;
+                while ethertype_pos + 2 <= frame.len() &&
+                        read_u16_be(frame, ethertype_pos) == ETHERTYPE_VLAN {
+                    ethertype_pos += 4;
+                }
+                if ethertype_pos + 2 > frame.len() {
+                    loop;
+                }
+                let ethertype = read_u16_be(frame, ethertype_pos);
+                if ethertype != ETHERTYPE_IPV4 && ethertype != ETHERTYPE_IPV6 {
+                    loop;
+                }
+                ethertype_pos + 2
+            }
+            LINKTYPE_NULL => 4,
+            LINKTYPE_LINUX_SLL => 16,
+            _ => 0
+        };
+        if ip_start > frame.len() {
+            loop;
+        }
+        match udp_payload(view(frame, ip_start, frame.len()), port) {
+            Some(payload) => f(payload),
+            None => {}
+        }
+    }
+    return OK(());
+}
+
+// Lossless gain
+//
+// Layer II scale factors are 6-bit indices into SCF_VALUE, in 2 dB steps with 0 the loudest.
+// Offsetting every index changes the gain without requantizing, so there's no generation loss.
+// Indices must stay within 0-62: a frame that would need an index below 0 is counted as clipped
+// and left at full scale, and indices pushed above 62 stay at the quietest level. The MPEG CRC
+// doesn't cover scale factors, so it stays valid; the DAB ScF-CRC does and is rewritten.
+
+struct GainReport {
+    mut frames: uint;
+    mut clipped_frames: uint;
+    mut skipped_bytes: uint;    // Bytes that weren't part of a valid frame, left unchanged
+}
+
+// Offsets all scale factors in the given audio by the given number of 2 dB steps, positive for
+// louder. Any VBR header frame must already be skipped. With dab set, the ScF-CRCs carried by
+// each frame for the next are rewritten.
+fn adjust_gain(context: &MP2Context, audio: &[mut u8], steps: i32, dab: bool) -> GainReport {
+    let report = GainReport { frames: 0, clipped_frames: 0, skipped_bytes: 0 };
+    let mut pos = 0;
+    let mut prev_frame = None;      // Offset and size of the previous frame
+    while pos + 4 <= audio.len() {
+        let frame = mut_view(audio, pos, audio.len());
+        let info = match read_side_info(context, frame) {
+            OK(info) => info,
+            Error(_) => {
+                // Resynchronize, leaving the bytes in between alone.
+                let skip = match find_sync(view(audio, pos + 1, audio.len())) {
+                    Some(offset) => offset + 1,
+                    None => audio.len() - pos
+                };
+                report.skipped_bytes += skip;
+                pos += skip;
+                prev_frame = None;
+                loop;
+            }
+        };
+
+        if adjust_frame_gain(frame, &info, steps) {
+            report.clipped_frames += 1;
+        }
+        if dab {
+            match prev_frame {
+                Some((prev_pos, prev_size)) => {
+                    let words = [ mut 0u8, 0, 0, 0 ];
+                    let count = scf_crc_size(&info.header);
+                    info.scf_crc_words(mut_view(words, 0, count));
+                    for uint::range(0, count) |i| {
+                        audio[prev_pos + prev_size - 3 - i] = words[i];
+                    }
+                }
+                None => {}
+            }
+        }
+
+        let size = info.header.frame_size();
+        prev_frame = Some((pos, size));
+        report.frames += 1;
+        pos += size;
+    }
+    return report;
+}
+
+// Offsets a scale factor by the given number of 2 dB steps, returning it and whether it had to
+// be limited to full scale. Scale factor 63 is silence, and stays silent.
+fn offset_scalefactor(scalefactor: u8, steps: i32) -> (u8, bool) {
+    if scalefactor == 63 {
+        return (63, false);
+    }
+    let scalefactor = (scalefactor as i32) - steps;
+    if scalefactor < 0 {
+        (0, true)
+    } else if scalefactor > 62 {
+        (62, false)
+    } else {
+        (scalefactor as u8, false)
+    }
+}
+
+// Offsets the scale factors of one frame, updating the side information to match. Returns true
+// if any scale factor had to be limited to full scale.
+fn adjust_frame_gain(frame: &[mut u8], info: &SideInfo, steps: i32) -> bool {
+    let mut clipped = false;
+    for uint::range(0, info.sblimit) |sb| {
+        for uint::range(0, info.num_channels) |ch| {
+            if info.quantizer[ch][sb] == 0 {
+                loop;
+            }
+            let mut bit = info.scalefactor_bit[ch][sb];
+            for SCF_TRANSMITTED[info.scfsi[ch][sb] as uint].each |part| {
+                if *part < 0 {
+                    break;
+                }
+                let (scalefactor, limited) = offset_scalefactor(info.scalefactor[ch][sb][*part],
+                                                                steps);
+                clipped = clipped || limited;
+                put_bits(frame, bit, 6, scalefactor as uint);
+                bit += 6;
+            }
+
+            // Update the copies of the transmitted scale factors too.
+            for uint::range(0, 3) |part| {
+                let (scalefactor, _) = offset_scalefactor(info.scalefactor[ch][sb][part], steps);
+                info.scalefactor[ch][sb][part] = scalefactor;
+            }
+        }
+    }
+    return clipped;
+}
+
+// Runs "fempeg gain": writes a copy of a file with its gain changed.
+fn run_gain(context: &MP2Context, args: &[UniqueString]) {
+    let mut dab = false;
+    let mut params = ~[];
+    for uint::range(2, args.len()) |i| {
+        if args[i] == ~"--dab" {
+            dab = true;
+        } else {
+            params.push(copy args[i]);
+        }
+    }
+    let steps = if params.len() == 3 {
+        let text = if str::starts_with(params[0], "+") {
+            str::slice(params[0], 1, params[0].len())
+        } else {
+            copy params[0]
+        };
+        int::from_str(text)
+    } else {
+        None
+    };
+    let steps = match steps {
+        Some(steps) if steps > -63 && steps < 63 => steps as i32,
+        _ => {
+            println(fmt!("usage: %s gain [--dab] STEPS input.mp2 output.mp2", args[0]));
+            println("Changes the gain by STEPS times 2 dB without decoding.");
+            return;
+        }
+    };
+
+    let result = io::read_whole_file(params[1]);
+    let bytes = match result {
+        OK(_)    => unwrap(result),
+        Error(e) => { println(e); return; }
+    };
+    let output = vec::to_mut(bytes);
+
+    // Tags are copied as they are. So is the VBR header frame, whose size and seek table still
+    // apply.
+    let (start, end) = {
+        let tags = Tags(output);
+        let start = tags.id3v2_size + match read_vbr_info(tags.audio) {
+            Some(ref info) => info.header_size,
+            None => 0
+        };
+        (start, tags.id3v2_size + tags.audio.len())
+    };
+    let report = adjust_gain(context, mut_view(output, start, end), steps, dab);
+    println(fmt!("%u frames adjusted by %d dB", report.frames, (steps * 2) as int));
+    if report.clipped_frames > 0 {
+        println(fmt!("%u frames clipped", report.clipped_frames));
+    }
+    if report.skipped_bytes > 0 {
+        println(fmt!("%u bytes outside valid frames left unchanged", report.skipped_bytes));
+    }
+
+    match io::file_writer(params[2], ~[io::Create, io::Truncate]) {
+        OK(writer) => writer.write(output),
+        Error(e) => println(e)
+    }
+}
+
+// Cutting and joining
+//
+// Layer II frames decode independently, so a stream can be cut at any frame boundary by copying
+// frames. A cut covers the frames that overlap the requested time range; the samples before the
+// start and after the end within those frames are reported, and written to a new Xing/LAME
+// header as encoder delay and padding, so that gapless players trim them. ID3v2 tags are kept.
+
+// A run of whole frames covering a time range of a stream
+struct FrameRange {
+    start: uint;            // Byte offsets in the audio
+    end: uint;
+    frames: uint;
+    lead_samples: uint;     // Samples in the frames before the start of the range
+    trail_samples: uint;    // Samples in the frames after the end of the range
+}
+
+// Finds the frames in the audio (after any VBR header frame) covering the given range of
+// samples, given the encoder delay and padding. Without an end, the range runs to the end of the
+// stream.
+fn frame_range(audio: &[u8], delay: uint, padding: uint, start_sample: uint,
+               end_sample: option<uint>) -> MP2Result<FrameRange> {
+    // Frame f holds the samples from f * SAMPLES_PER_FRAME - delay.
+    let first_frame = (start_sample + delay) / SAMPLES_PER_FRAME;
+    let last_frame = match end_sample {
+        Some(end) => (end + delay + SAMPLES_PER_FRAME - 1) / SAMPLES_PER_FRAME,
+        None => uint::max_value
+    };
+    if last_frame <= first_frame {
+        return Error("empty range");
+    }
+
+    let mut pos = match find_sync(audio) {
+        Some(pos) => pos,
+        None => return Error("no MPEG-Audio Layer II frames found")
+    };
+    let mut frame = 0;
+    let mut start = None;
+    while frame < last_frame && pos + 4 <= audio.len() {
+        let size = match parse_header(view(audio, pos, audio.len())) {
+            OK(header) if pos + header.frame_size() <= audio.len() => header.frame_size(),
+            _ => match find_sync(view(audio, pos + 1, audio.len())) {
+                // Skip junk between frames.
+                Some(offset) => { pos += 1 + offset; loop; }
+                None => break
+            }
+        };
+        if frame == first_frame {
+            start = Some(pos);
+        }
+        pos += size;
+        frame += 1;
+    }
+    let start = match start {
+        Some(start) => start,
+        None => return Error("the start is past the end of the stream")
+    };
+
+    // A range running to the end of the stream keeps the original padding.
+    let range_end = match end_sample {
+        Some(end) if frame == last_frame => end + delay,
+        _ => frame * SAMPLES_PER_FRAME - uint::min(padding, SAMPLES_PER_FRAME)
+    };
+    return OK(FrameRange {
+        start: start,
+        end: pos,
+        frames: frame - first_frame,
+        lead_samples: start_sample + delay - first_frame * SAMPLES_PER_FRAME,
+        trail_samples: frame * SAMPLES_PER_FRAME - range_end
+    });
+}
+
+// Makes a Xing/Info header frame for the given frames, using the parameters of the first. The
+// header frame is silent: its allocations are all zero. Returns None if the frame is too small
+// to hold the header. With a LAME version string, the LAME extension carries the delay and
+// padding.
+fn make_vbr_header(audio: &[u8], frames: uint, lame_version: option<~[u8]>, delay: uint,
+                   padding: uint) -> option<~[mut u8]> {
+    let header = match parse_header(audio) {
+        OK(header) => header,
+        Error(_) => return None
+    };
+    let size = header.frame_size();
+    let pos = 4 + (if header.mode == Mono { 17 } else { 32 });
+    let tag_size = 8 + 4 + 4 + 100 + (if lame_version.is_some() { 24 } else { 0 });
+    if pos + tag_size > size {
+        return None;
+    }
+
+    // "Info" marks a constant bit rate stream.
+    let mut constant = true;
+    let mut offset = 0;
+    while offset < audio.len() {
+        match parse_header(view(audio, offset, audio.len())) {
+            OK(next) => {
+                constant &= next.bit_rate_index == header.bit_rate_index;
+                offset += next.frame_size();
+            }
+            Error(_) => break
+        }
+    }
+
+    let frame = vec::to_mut(vec::from_elem(size, 0u8));
+    for uint::range(0, 4) |i| {
+        frame[i] = audio[i];
+    }
+    frame[1] |= 1;      // No CRC
+    let kind = if constant { "Info" } else { "Xing" };
+    for uint::range(0, 4) |i| {
+        frame[pos + i] = kind[i];
+    }
+    let put_u32 = |offset: uint, value: uint| {
+        for uint::range(0, 4) |i| {
+            frame[offset + i] = (value >> (24 - i * 8)) as u8;
+        }
+    };
+    put_u32(pos + 4, 7);                    // Frames, bytes and TOC present
+    put_u32(pos + 8, frames);               // Not counting the header frame, as LAME does
+    let total = size + audio.len();
+    put_u32(pos + 12, total);
+
+    // The TOC gives the offset of the frame at each percent of the stream, in 1/256ths of the
+    // total size.
+    let mut offset = 0;
+    let mut frame_index = 0;
+    for uint::range(0, 100) |i| {
+        let target = i * frames / 100;
+        while frame_index < target {
+            offset += match parse_header(view(audio, offset, audio.len())) {
+                OK(header) => header.frame_size(),
+                Error(_) => break
+            };
+            frame_index += 1;
+        }
+        frame[pos + 16 + i] = uint::min((size + offset) * 256 / total, 255) as u8;
+    }
+
+    match lame_version {
+        Some(ref version) => {
+            let lame = pos + 116;
+            for uint::range(0, uint::min(version.len(), 9)) |i| {
+                frame[lame + i] = version[i];
+            }
+            let value = (uint::min(delay, 0xfff) << 12) | uint::min(padding, 0xfff);
+            frame[lame + 21] = (value >> 16) as u8;
+            frame[lame + 22] = (value >> 8) as u8;
+            frame[lame + 23] = value as u8;
+        }
+        None => {}
+    }
+    return Some(frame);
+}
+
+// Returns the LAME version string of a Xing header frame, if it has one.
+fn lame_version(frame: &[u8]) -> option<~[u8]> {
+    let mut pos = 4;
+    while pos + 9 <= frame.len() {
+        if has_magic(frame, pos, "LAME") || has_magic(frame, pos, "L3.9") {
+            return Some(vec::from_slice(view(frame, pos, pos + 9)));
+        }
+        pos += 1;
+    }
+    return None;
+}
+
+// Parses a time such as "10.5s", "10.5" or "1:02.5", returning seconds.
+fn parse_time(text: String) -> option<float> {
+    let text = if str::ends_with(text, "s") { str::slice(text, 0, text.len() - 1) } else {
+        str::from_slice(text)
+    };
+    match str::find_char(text, ':') {
+        Some(pos) => {
+            let minutes = uint::from_str(str::slice(text, 0, pos));
+            let seconds = float::from_str(str::slice(text, pos + 1, text.len()));
+            match (minutes, seconds) {
+                (Some(minutes), Some(seconds)) if seconds >= 0.0 => {
+                    Some((minutes as float) * 60.0 + seconds)
+                }
+                _ => None
+            }
+        }
+        None => match float::from_str(text) {
+            Some(seconds) if seconds >= 0.0 => Some(seconds),
+            _ => None
+        }
+    }
+}
+
+// Runs "fempeg cut": copies the frames covering a time range to a new file.
+fn run_cut(args: &[UniqueString]) {
+    let mut start = 0.0;
+    let mut end = None;
+    let mut paths = ~[];
+    let mut i = 2;
+    let mut valid = true;
+    while i < args.len() {
+        if (args[i] == ~"--start" || args[i] == ~"--end") && i + 1 < args.len() {
+            match parse_time(args[i + 1]) {
+                Some(seconds) if args[i] == ~"--start" => start = seconds,
+                Some(seconds) => end = Some(seconds),
+                None => valid = false
+            }
+            i += 2;
+        } else {
+            paths.push(copy args[i]);
+            i += 1;
+        }
+    }
+    if !valid || paths.len() != 2 {
+        println(fmt!("usage: %s cut [--start TIME] [--end TIME] input.mp2 output.mp2", args[0]));
+        return;
+    }
+
+    let result = io::read_whole_file(paths[0]);
+    let bytes = match result {
+        OK(_)    => unwrap(result),
+        Error(e) => { println(e); return; }
+    };
+    let bytes = view(bytes, 0, bytes.len());
+    let tags = Tags(bytes);
+    let vbr = read_vbr_info(tags.audio);
+    let (audio, delay, padding) = match vbr {
+        Some(ref info) if info.has_gapless_info => {
+            (view(tags.audio, info.header_size, tags.audio.len()), info.encoder_delay,
+             info.encoder_padding)
+        }
+        Some(ref info) => (view(tags.audio, info.header_size, tags.audio.len()), 0, 0),
+        None => (tags.audio, 0, 0)
+    };
+    let sample_rate = match find_sync(audio) {
+        Some(pos) => parse_header(view(audio, pos, audio.len())).get().sample_rate(),
+        None => { println("no MPEG-Audio Layer II frames found"); return; }
+    };
+
+    let start_sample = (start * (sample_rate as float)) as uint;
+    let end_sample = end.map(|seconds| (*seconds * (sample_rate as float)) as uint);
+    let range = match frame_range(audio, delay, padding, start_sample, end_sample) {
+        OK(range) => range,
+        Error(e) => { println(e); return; }
+    };
+    println(fmt!("copying %u frames", range.frames));
+    if range.lead_samples > 0 {
+        println(fmt!("start falls %u samples into the first frame", range.lead_samples));
+    }
+    if range.trail_samples > 0 {
+        println(fmt!("end falls %u samples before the end of the last frame",
+                     range.trail_samples));
+    }
+
+    // Write the ID3v2 tag, a new VBR header if the original had one or the cut isn't on frame
+    // boundaries, the frames, and the APE and ID3v1 tags.
+    let frames = view(audio, range.start, range.end);
+    let mut output = ~[];
+    vec::push_all(&mut output, view(bytes, 0, tags.id3v2_size));
+    let version = match vbr {
+        Some(ref info) => match info.kind {
+            Xing => lame_version(view(tags.audio, 0, info.header_size)),
+            VBRI => None
+        },
+        None => None
+    };
+    // Readers only look for the "LAME" magic, so any version string carries the gapless info.
+    let gapless = range.lead_samples > 0 || range.trail_samples > 0;
+    let version = if version.is_none() && gapless { Some(str::to_bytes("LAME     ")) } else {
+        version
+    };
+    if vbr.is_some() || gapless {
+        match make_vbr_header(frames, range.frames, version, range.lead_samples,
+                              range.trail_samples) {
+            Some(header) => vec::push_all(&mut output, header),
+            None => println("frames too small for a VBR header; gapless information is lost")
+        }
+    }
+    vec::push_all(&mut output, frames);
+    vec::push_all(&mut output, view(bytes, tags.id3v2_size + tags.audio.len(), bytes.len()));
+
+    match io::file_writer(paths[1], ~[io::Create, io::Truncate]) {
+        OK(writer) => writer.write(output),
+        Error(e) => println(e)
+    }
+}
+
+// Runs "fempeg join": concatenates the frames of files with the same sample rate and channel
+// mode. The first file's tags are kept. If any file had a VBR header, a new one is written for
+// the joined frames, with the first file's encoder delay and the last file's padding; delay and
+// padding inside the joined stream stays.
+fn run_join(args: &[UniqueString]) {
+    if args.len() < 5 {
+        println(fmt!("usage: %s join output.mp2 input.mp2 input.mp2...", args[0]));
+        return;
+    }
+
+    let mut output = ~[];
+    let mut frames = ~[];
+    let mut trailing_tags = ~[];
+    let mut format = None;
+    let mut has_vbr_header = false;
+    let mut version = None;
+    let mut delay = 0;
+    let mut padding = 0;
+    for uint::range(3, args.len()) |i| {
+        let result = io::read_whole_file(args[i]);
+        let bytes = match result {
+            OK(_)    => unwrap(result),
+            Error(e) => { println(e); return; }
+        };
+        let bytes = view(bytes, 0, bytes.len());
+        let tags = Tags(bytes);
+        if i == 3 {
+            vec::push_all(&mut output, view(bytes, 0, tags.id3v2_size));
+            trailing_tags = vec::from_slice(view(bytes, tags.id3v2_size + tags.audio.len(),
+                                                 bytes.len()));
+        }
+        let vbr = read_vbr_info(tags.audio);
+        let (audio, file_delay, file_padding) = match vbr {
+            Some(ref info) if info.has_gapless_info => {
+                (view(tags.audio, info.header_size, tags.audio.len()), info.encoder_delay,
+                 info.encoder_padding)
+            }
+            Some(ref info) => (view(tags.audio, info.header_size, tags.audio.len()), 0, 0),
+            None => (tags.audio, 0, 0)
+        };
+        if i == 3 {
+            delay = file_delay;
+            version = match vbr {
+                Some(ref info) => match info.kind {
+                    Xing => lame_version(view(tags.audio, 0, info.header_size)),
+                    VBRI => None
+                },
+                None => None
+            };
+        }
+        padding = file_padding;
+        has_vbr_header = has_vbr_header || vbr.is_some();
+
+        let pos = match find_sync(audio) {
+            Some(pos) => pos,
+            None => { println(fmt!("%s: no MPEG-Audio Layer II frames found", args[i])); return; }
+        };
+        let header = parse_header(view(audio, pos, audio.len())).get();
+        let this_format = (header.sampling_frequency, header.mode);
+        if format.is_some() && format != Some(this_format) {
+            println(fmt!("%s: sample rate or channel mode differs from the first file", args[i]));
+            return;
+        }
+        format = Some(this_format);
+        vec::push_all(&mut frames, view(audio, pos, audio.len()));
+    }
+
+    // Readers only look for the "LAME" magic, so any version string carries the gapless info.
+    let gapless = delay > 0 || padding > 0;
+    let version = if version.is_none() && gapless { Some(str::to_bytes("LAME     ")) } else {
+        version
+    };
+    if has_vbr_header || gapless {
+        let count = match frame_range(frames, 0, 0, 0, None) {
+            OK(range) => range.frames,
+            Error(_) => 0
+        };
+        match make_vbr_header(frames, count, version, delay, padding) {
+            Some(header) => vec::push_all(&mut output, header),
+            None => println("frames too small for a VBR header; gapless information is lost")
+        }
+    }
+    vec::push_all(&mut output, frames);
+    vec::push_all(&mut output, trailing_tags);
+
+    match io::file_writer(args[2], ~[io::Create, io::Truncate]) {
+        OK(writer) => writer.write(output),
+        Error(e) => println(e)
+    }
+}
+
+// Validation
+//
+// Decoding glosses over streams that break the rules of ISO 11172-3 Layer II, as long as they
+// can be decoded. The validator checks every frame strictly instead, and reports each problem.
+
+// Bit rates (as indices into BITRATES) allowed only in mono, and only in the other modes
+const MONO_ONLY_BIT_RATES: [i32]/4 = [ 1, 2, 3, 5 ];            // 32, 48, 56 and 80 kbit/s
+const STEREO_ONLY_BIT_RATES: [i32]/4 = [ 11, 12, 13, 14 ];      // 224 kbit/s and above
+
+struct Validator {
+    context: &MP2Context;
+    mut frames: uint;
+    mut invalid_frames: uint;
+    mut skipped_bytes: uint;
+
+    // At 44.1 kHz, frames are padded now and then to keep the average bit rate. This is the
+    // running difference between the padding sent and the padding due, in 1/44100ths of a byte,
+    // for the bit rate it applies to.
+    mut padding_bit_rate_index: i32;
+    mut padding_error: int;
+
+    // A copy of the frame being checked, followed by zeros for the bitstream to read ahead into
+    frame: [mut u8]/1731;      // Largest frame: 384 kbit/s at 32 kHz, padded; plus two bytes.
+}
+
+fn Validator(context: &MP2Context) -> Validator {
+    Validator {
+        context: context,
+        frames: 0,
+        invalid_frames: 0,
+        skipped_bytes: 0,
+        padding_bit_rate_index: 0,
+        padding_error: 0,
+        frame: [ mut 0, ..1731 ]
+    }
+}
+
+impl Validator {
+    // Checks a stream, calling the given function with the offset of each frame that breaks the
+    // rules and a description of each problem.
+    fn check(audio: &[u8], problem: fn(uint, String)) {
+        let mut pos = 0;
+        while pos + 4 <= audio.len() {
+            let size = match parse_header(view(audio, pos, audio.len())) {
+                OK(header) => header.frame_size(),
+                Error(e) => {
+                    let skip = match find_sync(view(audio, pos + 1, audio.len())) {
+                        Some(offset) => offset + 1,
+                        None => audio.len() - pos
+                    };
+                    problem(pos, fmt!("%s; %u bytes skipped", e, skip));
+                    self.skipped_bytes += skip;
+                    self.padding_bit_rate_index = 0;
+                    pos += skip;
+                    loop;
+                }
+            };
+            if pos + size > audio.len() {
+                problem(pos, fmt!("truncated frame: %u of %u bytes", audio.len() - pos, size));
+                self.invalid_frames += 1;
+                self.frames += 1;
+                return;
+            }
+
+            let mut valid = true;
+            do self.check_frame(view(audio, pos, pos + size)) |text| {
+                problem(pos, text);
+                valid = false;
+            }
+            if !valid {
+                self.invalid_frames += 1;
+            }
+            self.frames += 1;
+            pos += size;
+        }
+    }
+
+    // Checks one complete frame, calling the given function with a description of each problem.
+    fn check_frame(frame: &[u8], problem: fn(String)) {
+        let header = match parse_header(frame) {
+            OK(header) => header,
+            Error(e) => { problem(e); return; }
+        };
+
+        if header.mode == Mono && vec::contains(STEREO_ONLY_BIT_RATES, &header.bit_rate_index) {
+            problem(fmt!("%d kbit/s is not allowed in mono", header.bit_rate() as int));
+        }
+        if header.mode != Mono && vec::contains(MONO_ONLY_BIT_RATES, &header.bit_rate_index) {
+            problem(fmt!("%d kbit/s is only allowed in mono", header.bit_rate() as int));
+        }
+        if header.emphasis == 2 {
+            problem("reserved emphasis");
+        }
+        self.check_padding(&header, problem);
+
+        let info = match read_side_info(self.context, frame) {
+            OK(info) => info,
+            Error(e) => { problem(e); return; }
+        };
+        let frame = view(frame, 0, header.frame_size());
+        if header.protected {
+            let crc = read_u16_be(frame, 4);
+            let expected = frame_crc(frame, info.crc_end_bit);
+            if crc != expected {
+                problem(fmt!("CRC is %04x, should be %04x", crc, expected));
+            }
+        }
+
+        let mut sample_bits = 0;
+        for uint::range(0, info.sblimit) |sb| {
+            for uint::range(0, info.num_channels) |ch| {
+                // Above the bound, channel 1 shares channel 0's allocation.
+                let transmitted = ch == 0 || sb < info.bound;
+                let code = info.allocation_code[ch][sb];
+                let quantizer = info.quantizer[ch][sb] as uint;
+                if transmitted && code != 0 && quantizer == 0 {
+                    problem(fmt!("illegal allocation code %u in subband %u",
+                                 code as uint, sb));
+                }
+                if quantizer != 0 {
+                    for uint::range(0, 3) |part| {
+                        if info.scalefactor[ch][sb][part] == 63 {
+                            problem(fmt!("scale factor 63 in subband %u", sb));
+                        }
+                    }
+                    if transmitted {
+                        let q = &self.context.constants.QUANTIZER_TABLE[quantizer - 1];
+                        sample_bits += (q.cw_bits as uint) * (if q.grouping != 0 { 1 } else { 3 });
+                    }
+                }
+            }
+        }
+
+        // Samples: twelve granules, each with three samples (or one grouped codeword) per
+        // subband and channel. The bitstream reads up to two bytes ahead, so the samples are read
+        // from a copy of the frame followed by zeros.
+        let samples_end = info.samples_bit + sample_bits * 12;
+        if samples_end > frame.len() * 8 {
+            problem(fmt!("samples overrun the frame by %u bits", samples_end - frame.len() * 8));
+            return;
+        }
+        for uint::range(0, self.frame.len()) |i| {
+            self.frame[i] = if i < frame.len() { frame[i] } else { 0 };
+        }
+        let bitstream = Bitstream {
+            bit_window: (self.frame[info.samples_bit / 8] as i32) << 16,
+            bits_in_window: 8,
+            frame_pos: view(self.frame, info.samples_bit / 8 + 1, self.frame.len()),
+            bytes_past_end: 0
+        };
+        ignore(bitstream.get_bits((info.samples_bit % 8) as i32));
+        let mut reported = false;
+        for uint::range(0, 12) |_granule| {
+            for uint::range(0, info.sblimit) |sb| {
+                for uint::range(0, info.num_channels) |ch| {
+                    let quantizer = info.quantizer[ch][sb] as uint;
+                    if quantizer != 0 && (ch == 0 || sb < info.bound) {
+                        let q = &self.context.constants.QUANTIZER_TABLE[quantizer - 1];
+                        let nlevels = q.nlevels as i32;
+                        let codes = if q.grouping != 0 { 1 } else { 3 };
+                        let limit = if q.grouping != 0 { nlevels * nlevels * nlevels } else {
+                            nlevels
+                        };
+                        for uint::range(0, codes) |_i| {
+                            let code = bitstream.get_bits(q.cw_bits as i32);
+                            if code >= limit && !reported {
+                                // Once per frame is enough.
+                                problem(fmt!("sample code %d in subband %u is out of range",
+                                             code as int, sb));
+                                reported = true;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Checks the padding bit. Frames at 32 and 48 kHz are a whole number of bytes, so need no
+    // padding; at 44.1 kHz the padding must keep the average bit rate, to within a byte.
+    fn check_padding(header: &FrameHeader, problem: fn(String)) {
+        let sample_rate = header.sample_rate() as int;
+        let remainder = (144000 * header.bit_rate()) as int % sample_rate;
+        if remainder == 0 {
+            if header.padding_bit != 0 {
+                problem(fmt!("padding at %d kbit/s and %d Hz", header.bit_rate() as int,
+                             sample_rate));
+            }
+            return;
+        }
+        if header.bit_rate_index != self.padding_bit_rate_index {
+            self.padding_bit_rate_index = header.bit_rate_index;
+            self.padding_error = 0;
+        }
+        self.padding_error += (header.padding_bit as int) * sample_rate - remainder;
+        if self.padding_error > sample_rate || self.padding_error < -sample_rate {
+            problem(if self.padding_error > 0 { "too much padding" } else {
+                "too little padding"
+            });
+            self.padding_error = 0;
+        }
+    }
+}
+
+// Runs "fempeg validate": checks a file and reports each frame that breaks the rules.
+fn run_validate(context: &MP2Context, args: &[UniqueString]) {
+    if args.len() != 3 {
+        println(fmt!("usage: %s validate input.mp2", args[0]));
+        return;
+    }
+    let result = io::read_whole_file(args[2]);
+    let bytes = match result {
+        OK(_)    => unwrap(result),
+        Error(e) => { println(e); return; }
+    };
+    let bytes = view(bytes, 0, bytes.len());
+    let tags = Tags(bytes);
+
+    // The VBR header frame is deliberately silent and carries no samples, so it's not checked.
+    let start = match read_vbr_info(tags.audio) {
+        Some(ref info) => info.header_size,
+        None => 0
+    };
+    let validator = Validator(context);
+    let mut last_pos = uint::max_value;
+    do validator.check(view(tags.audio, start, tags.audio.len())) |pos, text| {
+        // Report each problem frame once, with its problems below.
+        let offset = tags.id3v2_size + start + pos;
+        if pos != last_pos {
+            println(fmt!("frame at offset %u:", offset));
+            last_pos = pos;
+        }
+        println(fmt!("    %s", text));
+    }
+    println(fmt!("%u frames, %u invalid", validator.frames, validator.invalid_frames));
+    if validator.skipped_bytes > 0 {
+        println(fmt!("%u bytes outside valid frames", validator.skipped_bytes));
+    }
+}
+
+// Conformance testing
+//
+// ISO/IEC 11172-4 tests a decoder by decoding the conformance bitstreams and comparing the output
+// with reference PCM, as fractions of full scale. A "full accuracy" decoder keeps the RMS of the
+// difference below 2^-15 / sqrt(12) and every difference within 2^-14; a "limited accuracy"
+// decoder keeps the RMS below 2^-11 / sqrt(12). Rounding to 16 bits alone comes to about
+// 2^-15 / sqrt(12), so full accuracy needs the filterbank to be very nearly exact.
+//
+// The reference files are raw big-endian PCM, interleaved when stereo, with either 16 or 24-bit
+// samples; the width is worked out from the file size.
+
+enum Accuracy {
+    FullAccuracy,
+    LimitedAccuracy,
+    NotCompliant
+}
+
+struct ConformanceResult {
+    // Samples per channel decoded and in the reference; the shorter is compared
+    decoded_samples: uint;
+    reference_samples: uint;
+    rms_error: float;       // Fractions of full scale
+    max_error: float;
+}
+
+impl ConformanceResult {
+    fn accuracy() -> Accuracy {
+        let limit = 1.0 / float::sqrt(12.0);
+        if self.decoded_samples == 0 || self.decoded_samples != self.reference_samples {
+            NotCompliant
+        } else if self.rms_error < limit / 32768.0 && self.max_error <= 1.0 / 16384.0 {
+            FullAccuracy
+        } else if self.rms_error < limit / 2048.0 {
+            LimitedAccuracy
+        } else {
+            NotCompliant
+        }
+    }
+}
+
+// Decodes a stream and compares the output with the reference PCM. Nothing is done to the
+// output: no trimming, de-emphasis or gain.
+fn check_conformance(context: &MP2Context, audio: &[u8], reference: &[u8])
+                  -> MP2Result<ConformanceResult> {
+    let header = match find_sync(audio) {
+        Some(pos) => parse_header(view(audio, pos, audio.len())).get(),
+        None => return Error("no MPEG-Audio Layer II frames found")
+    };
+    let channels = header.num_channels() as uint;
+
+    // Count the frames to tell 16-bit from 24-bit reference samples.
+    let mut frames = 0;
+    let mut pos = 0;
+    while pos + 4 <= audio.len() {
+        match parse_header(view(audio, pos, audio.len())) {
+            OK(header) if pos + header.frame_size() <= audio.len() => {
+                frames += 1;
+                pos += header.frame_size();
+            }
+            _ => pos += 1
+        }
+    }
+    let decoded = frames * SAMPLES_PER_FRAME * channels;
+    let difference = |bytes: uint| {
+        let count = reference.len() / bytes;
+        if count > decoded { count - decoded } else { decoded - count }
+    };
+    let width = if difference(3) < difference(2) { 3 } else { 2 };
+    let scale = if width == 3 { 8388608.0 } else { 32768.0 };
+    let reference_samples = reference.len() / (width * channels);
+
+    let stream = MP2Stream(context);
+    stream.deemphasis = false;
+    let pcm = [ mut 0i16, ..2304 ];
+    let mut sample = 0;
+    let mut sum_squares = 0.0;
+    let mut max_error = 0.0;
+    let mut pos = 0;
+    while pos + 4 <= audio.len() {
+        let frame = view(audio, pos, audio.len());
+        let size = match parse_header(frame) {
+            OK(header) if header.frame_size() <= frame.len() => {
+                match stream.decode_frame(frame, pcm) {
+                    OK(size) => size as uint,
+                    Error(e) => return Error(e)
+                }
+            }
+            _ => { pos += 1; loop; }
+        };
+        pos += size;
+
+        // Mono frames decode to two identical channels.
+        for uint::range(0, SAMPLES_PER_FRAME) |i| {
+            if sample < reference_samples {
+                for uint::range(0, channels) |ch| {
+                    let offset = (sample * channels + ch) * width;
+                    let mut value = (reference[offset] as i8) as int;
+                    for uint::range(1, width) |j| {
+                        value = (value << 8) | (reference[offset + j] as int);
+                    }
+                    let error = float::abs((pcm[i * 2 + ch] as float) / 32768.0 -
+                                           (value as float) / scale);
+                    sum_squares += error * error;
+                    max_error = float::fmax(max_error, error);
+                }
+            }
+            sample += 1;
+        }
+    }
+
+    let compared = uint::min(sample, reference_samples);
+    return OK(ConformanceResult {
+        decoded_samples: sample,
+        reference_samples: reference_samples,
+        rms_error: if compared > 0 {
+            float::sqrt(sum_squares / ((compared * channels) as float))
+        } else {
+            0.0
+        },
+        max_error: max_error
+    });
+}
+
+// Returns the path of the reference PCM for a file in a conformance test directory, or None if
+// the file isn't a bitstream.
+fn conformance_reference(dir: String, name: String) -> option<UniqueString> {
+    if str::ends_with(name, ".mp2") || str::ends_with(name, ".mpg") ||
+            str::ends_with(name, ".bit") {
+        Some(fmt!("%s/%s.pcm", dir, str::slice(name, 0, name.len() - 4)))
+    } else {
+        None
+    }
+}
+
+// Runs "fempeg conformance": checks each bitstream in a directory (.mp2, .mpg or .bit) against
+// the reference PCM beside it (.pcm), and reports the accuracy fempeg qualifies for.
+fn run_conformance(context: &MP2Context, args: &[UniqueString]) {
+    if args.len() != 3 {
+        println(fmt!("usage: %s conformance directory", args[0]));
+        return;
+    }
+    let dir = copy args[2];
+    let names = std::sort::merge_sort(|a, b| *a <= *b, os::list_dir(dir));
+
+    let mut tested = 0;
+    let mut overall = FullAccuracy;
+    for names.each |name| {
+        let reference_path = match conformance_reference(dir, *name) {
+            Some(path) => path,
+            None => loop
+        };
+        if !os::path_exists(reference_path) {
+            println(fmt!("%s: no reference %s; skipped", *name, reference_path));
+            loop;
+        }
+
+        let audio = io::read_whole_file(fmt!("%s/%s", dir, *name));
+        let reference = io::read_whole_file(reference_path);
+        let (audio, reference) = match (audio, reference) {
+            (OK(audio), OK(reference)) => (audio, reference),
+            (Error(e), _) | (_, Error(e)) => { println(fmt!("%s: %s", *name, e)); loop; }
+        };
+        let result = match check_conformance(context, view(audio, 0, audio.len()),
+                                             view(reference, 0, reference.len())) {
+            OK(result) => result,
+            Error(e) => {
+                println(fmt!("%s: %s", *name, e));
+                overall = NotCompliant;
+                tested += 1;
+                loop;
+            }
+        };
+
+        let accuracy = result.accuracy();
+        println(fmt!("%s: RMS error %.2e, max error %.2e: %s", *name, result.rms_error,
+                     result.max_error, match accuracy {
+                         FullAccuracy => "full accuracy",
+                         LimitedAccuracy => "limited accuracy",
+                         NotCompliant => "not compliant"
+                     }));
+        if result.decoded_samples != result.reference_samples {
+            println(fmt!("    %u samples decoded, %u in the reference", result.decoded_samples,
+                         result.reference_samples));
+        }
+        overall = match (overall, accuracy) {
+            (NotCompliant, _) | (_, NotCompliant) => NotCompliant,
+            (LimitedAccuracy, _) | (_, LimitedAccuracy) => LimitedAccuracy,
+            _ => FullAccuracy
+        };
+        tested += 1;
+    }
+
+    if tested == 0 {
+        println("no bitstreams with reference PCM found");
+        return;
+    }
+    println(fmt!("%u bitstreams: %s", tested, match overall {
+        FullAccuracy => "fempeg qualifies as a full accuracy decoder",
+        LimitedAccuracy => "fempeg qualifies as a limited accuracy decoder",
+        NotCompliant => "fempeg does not qualify as a compliant decoder"
+    }));
+}
+
+// Fuzzing
+//
+// Each fuzz target takes arbitrary bytes and must return without failing, whatever they are: the
+// decoder reads frames from untrusted files and networks. The targets can be driven by an
+// external fuzzer, or by "fempeg fuzz", which runs them on a corpus of files and on random
+// mutations of each, and saves any input that fails. The seed corpus is in fuzz/corpus.
+
+// Parses a frame header, the side information, any VBR header and the tags.
+fn fuzz_header(context: &MP2Context, data: &[u8]) {
+    match parse_header(data) {
+        OK(header) => ignore(header.frame_size()),
+        Error(_) => {}
+    }
+    match read_side_info(context, data) {
+        OK(info) => {
+            ignore(frame_crc(data, info.crc_end_bit));
+            let words = [ mut 0u8, 0, 0, 0 ];
+            info.scf_crc_words(words);
+        }
+        Error(_) => {}
+    }
+    ignore(read_vbr_info(data));
+    let tags = Tags(data);
+    ignore(replaygain(&tags, ReplayGainTrack, 0.0));
+}
+
+// Decodes the data as a single frame, with everything that reads the frame afterwards.
+fn fuzz_frame(context: &MP2Context, data: &[u8]) {
+    let stream = MP2Stream(context);
+    stream.scf_crc_mode = ScfCrcReuse;
+    let pcm = [ mut 0i16, ..2304 ];
+    match stream.decode_frame(data, pcm) {
+        OK(_) => {
+            let ancillary = [ mut 0u8, ..1729 ];
+            ignore(stream.copy_ancillary_data(data, ancillary));
+            ignore(stream.ancillary_data(data));
+            let mc_decoder = McDecoder();
+            let mc_pcm = [ mut 0i16, ..6912 ];
+            ignore(mc_decoder.decode(&stream, data, pcm, mc_pcm));
+        }
+        Error(_) => stream.conceal_frame(pcm)
+    }
+    stream.flush(pcm);
+}
+
+// Feeds the data through the streaming API as the player does, in packets of varying size,
+// decoding each frame found.
+fn fuzz_stream(context: &MP2Context, data: &[u8]) {
+    let stream = MP2Stream(context);
+    let assembler = FrameAssembler();
+    let pcm = [ mut 0i16, ..2304 ];
+
+    // The first byte, if any, sets the packet size.
+    let packet_size = if data.len() > 0 { (data[0] as uint) * 16 + 1 } else { 1 };
+    let mut pos = 0;
+    let mut pts = 0u64;
+    while pos < data.len() {
+        let end = uint::min(pos + packet_size, data.len());
+        do assembler.push(view(data, pos, end), Some(pts)) |frame, _pts| {
+            fuzz_stream_frame(&stream, frame, pcm);
+        }
+        pos = end;
+        pts += 2160;
+    }
+    do assembler.finish |frame, _pts| {
+        fuzz_stream_frame(&stream, frame, pcm);
+    }
+    stream.flush(pcm);
+}
+
+fn fuzz_stream_frame(stream: &MP2Stream, frame: &[u8], pcm: &[mut i16]) {
+    match stream.decode_frame(frame, pcm) {
+        OK(_) => {}
+        Error(_) => stream.conceal_frame(pcm)
+    }
+    ignore(stream.format_change());
+}
+
+// Runs every fuzz target on the data.
+fn fuzz_all(data: &[u8]) {
+    let context = MP2Context();
+    fuzz_header(&context, data);
+    fuzz_frame(&context, data);
+    fuzz_stream(&context, data);
+}
+
+// A small xorshift generator, so that runs can be repeated
+struct FuzzRandom {
+    mut state: u32;
+}
+
+impl FuzzRandom {
+    fn next(limit: uint) -> uint {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        (self.state as uint) % limit
+    }
+
+    // Returns a copy of the data with a few bits or bytes changed, and possibly cut short.
+    fn mutate(data: &[u8]) -> ~[u8] {
+        let output = vec::to_mut(vec::from_slice(data));
+        let mut len = output.len();
+        for uint::range(0, 1 + self.next(8)) |_i| {
+            if len > 0 {
+                match self.next(8) {
+                    0..3 => {
+                        let pos = self.next(len);
+                        output[pos] ^= (1 << self.next(8)) as u8;
+                    }
+                    4..6 => output[self.next(len)] = self.next(256) as u8,
+                    _ => len = self.next(len + 1)
+                }
+            }
+        }
+        return vec::from_slice(view(output, 0, len));
+    }
+}
+
+// Runs "fempeg fuzz": runs the fuzz targets on each file in a corpus directory, then on the given
+// number of mutations of each. Each input runs in its own task, so a failure is caught and the
+// input saved as crash-N.mp2.
+fn run_fuzz(args: &[UniqueString]) {
+    let iterations = if args.len() == 4 { uint::from_str(args[3]) } else { Some(1000) };
+    let iterations = match iterations {
+        Some(iterations) if args.len() == 3 || args.len() == 4 => iterations,
+        _ => {
+            println(fmt!("usage: %s fuzz corpus-directory [iterations]", args[0]));
+            return;
+        }
+    };
+
+    let dir = copy args[2];
+    let names = std::sort::merge_sort(|a, b| *a <= *b, os::list_dir(dir));
+    let random = FuzzRandom { state: 0x2545f491 };
+    let mut runs = 0;
+    let mut crashes = 0;
+    for names.each |name| {
+        let result = io::read_whole_file(fmt!("%s/%s", dir, *name));
+        let seed = match result {
+            OK(_)    => unwrap(result),
+            Error(e) => { println(fmt!("%s: %s", *name, e)); loop; }
+        };
+        for uint::range(0, iterations + 1) |i| {
+            let input = if i == 0 { copy seed } else { random.mutate(seed) };
+            let saved = copy input;
+            if task::try(|| fuzz_all(input)).is_err() {
+                let path = fmt!("crash-%u.mp2", crashes);
+                println(fmt!("%s: input failed; saved as %s", *name, path));
+                match io::file_writer(path, ~[io::Create, io::Truncate]) {
+                    OK(writer) => writer.write(saved),
+                    Error(e) => println(e)
+                }
+                crashes += 1;
+            }
+            runs += 1;
+        }
+    }
+    println(fmt!("%u inputs, %u failures", runs, crashes));
+}
+
+// Playback
+
+// Most frames concealed for one loss of data, about 3 seconds
+const MAX_CONCEALED_FRAMES: uint = 128;
+
+struct Player {
+    stream: &self/MP2Stream;
+    ao: &self/ao::AO;
+    pcm: [mut i16]/2304;
+
+    // The multichannel decoder and its 6-channel output, if multichannel output is wanted
+    multichannel: option<McDecoder>;
+    mc_pcm: [mut i16]/6912;
+
+    // The output device, opened at the current sample rate and channel count (or the resampler's
+    // rate), and reopened when those change
+    mut device: option<ao::Device>;
+    mut sample_rate: i32;
+    mut channels: uint;
+    mono: [mut i16]/1152;
+
+    mut trimmer: option<Trimmer>;
+
+    // Converts the output to a fixed rate, if one was asked for
+    resampler: option<Resampler>;
+
+    // Measures the loudness of the output, if asked for; with audible unset, nothing is played.
+    meter: option<LoudnessMeter>;
+    audible: bool;
+
+    ancillary_writer: option<io::Writer>;
+    ancillary: [mut u8]/1729;   // Largest frame: 384 kbit/s at 32 kHz, padded.
+
+    pad_decoder: option<PadDecoder>;
+    slides_path: option<UniqueString>;
+    mut slide_count: uint;
+
+    // Timestamp of the next frame, in 90 kHz units, if known
+    show_timestamps: bool;
+    mut next_pts: option<u64>;
+
+    // Set when data was lost, until the frames lost are concealed
+    mut lost: bool;
+}
+
+impl Player {
+    // Decodes and plays a frame, returning its size. Frames that can't be decoded are concealed
+    // and None is returned.
+    fn play_frame(frame: &[u8], pts: option<u64>) -> option<uint> {
+        if self.lost {
+            self.conceal_lost(frame, pts);
+        }
+
+        // Make sure the whole frame is there before decoding it.
+        let result = match parse_header(frame) {
+            OK(header) if header.frame_size() > frame.len() => Error("truncated frame"),
+            OK(_) => self.stream.decode_frame(frame, self.pcm),
+            Error(e) => Error(e)
+        };
+        let frame_size = match result {
+            OK(size) => size as uint,
+            Error(e) => {
+                println(fmt!("%s; concealing", e));
+                self.conceal();
+                return None;
+            }
+        };
+        let header = parse_header(frame).get();
+        match self.stream.format_change() {
+            Some(format) => self.change_format(format),
+            None => {}
+        }
+
+        // Carry the timestamp forward to frames without one.
+        let pts = if pts.is_some() { pts } else { self.next_pts };
+        match pts {
+            Some(pts) => {
+                if self.show_timestamps {
+                    println(fmt!("PTS %.3f s", (pts as float) / 90000.0));
+                }
+                let duration = (SAMPLES_PER_FRAME as u64) * 90000 / (header.sample_rate() as u64);
+                self.next_pts = Some(pts + duration);
+            }
+            None => {}
+        }
+
+        match self.ancillary_writer {
+            Some(ref writer) => {
+                let bit_count = self.stream.copy_ancillary_data(frame, self.ancillary);
+                writer.write(view(self.ancillary, 0, bit_count / 8));
+            }
+            None => {}
+        }
+
+        match self.multichannel {
+            Some(ref mc) => {
+                mc.decode(self.stream, frame, self.pcm, self.mc_pcm);
+            }
+            None => {}
+        }
+
+        match self.pad_decoder {
+            Some(ref pad_decoder) => {
+                let ancillary = self.stream.ancillary_data(frame);
+                do pad_decoder.decode(ancillary, scf_crc_size(&header)) |event| {
+                    handle_pad_event(event, &self.slides_path, &mut self.slide_count);
+                }
+            }
+            None => {}
+        }
+
+        self.output();
+        return Some(frame_size);
+    }
+
+    // Notes that data was lost. The frames lost are concealed when the next frame arrives.
+    fn lose() {
+        self.lost = true;
+    }
+
+    // Conceals the frames lost before the given frame: as many as the gap between its timestamp
+    // and the one expected, or one if that can't be told.
+    fn conceal_lost(frame: &[u8], pts: option<u64>) {
+        self.lost = false;
+        let mut count = 1;
+        match (parse_header(frame), pts, self.next_pts) {
+            (OK(header), Some(pts), Some(expected)) if pts >= expected => {
+                let duration = (SAMPLES_PER_FRAME as u64) * 90000 / (header.sample_rate() as u64);
+                let frames = ((pts - expected + duration / 2) / duration) as uint;
+
+                // A longer gap is a jump in the timestamps rather than a loss.
+                if frames <= MAX_CONCEALED_FRAMES {
+                    count = frames;
+                }
+            }
+            _ => {}
+        }
+        for count.times {
+            self.conceal();
+        }
+    }
+
+    // Plays a concealed frame in place of one that was corrupt or lost.
+    fn conceal() {
+        self.stream.conceal_frame(self.pcm);
+        match self.multichannel {
+            Some(ref mc) => mc.conceal(self.stream, self.pcm, self.mc_pcm),
+            None => {}
+        }
+        self.output();
+    }
+
+    // Plays the tail of the stream that's still in the filterbank.
+    fn finish() {
+        match self.trimmer {
+            Some(ref trimmer) => {
+                self.stream.flush(self.pcm);
+                match self.multichannel {
+                    Some(ref mc) => {
+                        mc.flush(self.stream, self.pcm, self.mc_pcm);
+                        self.play(trimmer.trim_flush(self.mc_pcm, MC_OUTPUT_CHANNELS));
+                    }
+                    None => self.play(trimmer.trim_flush(self.pcm, 2))
+                }
+            }
+            None => {}
+        }
+
+        self.finish_resampler();
+
+        match self.meter {
+            Some(ref meter) => meter.report(),
+            None => {}
+        }
+    }
+
+    // Pushes out what's left in the resampler.
+    fn finish_resampler() {
+        match self.resampler {
+            Some(ref resampler) => match self.device {
+                Some(ref device) => do resampler.finish |out| { device.play(out); },
+                None => {}
+            },
+            None => {}
+        }
+    }
+
+    // Switches to a new decoder output format. The device is reopened if its channel count or
+    // sample rate must change; with a resampler, only the resampler's input rate changes.
+    fn change_format(format: AudioFormat) {
+        let channels = if self.multichannel.is_some() {
+            MC_OUTPUT_CHANNELS
+        } else {
+            format.channels
+        };
+        if self.sample_rate != 0 {
+            println(fmt!("format changed to %d Hz, %u channels", format.sample_rate as int,
+                         channels));
+        }
+        self.finish_resampler();
+        if channels != self.channels ||
+                (self.resampler.is_none() && format.sample_rate != self.sample_rate) {
+            self.device = None;
+        }
+        self.sample_rate = format.sample_rate;
+        self.channels = channels;
+    }
+
+    fn output() {
+        let (pcm, channels) = if self.multichannel.is_some() {
+            (mut_view(self.mc_pcm, 0, self.mc_pcm.len()), MC_OUTPUT_CHANNELS)
+        } else {
+            (mut_view(self.pcm, 0, self.pcm.len()), 2)
+        };
+        match self.trimmer {
+            Some(ref trimmer) => self.play(trimmer.trim(pcm, channels)),
+            None => self.play(pcm)
+        }
+    }
+
+    fn play(pcm: &[i16]) {
+        if self.sample_rate == 0 {
+            return;     // Nothing has been decoded yet.
+        }
+        // Mono streams are decoded to two identical channels; play one.
+        let channels = self.channels;
+        let pcm = if channels == 1 {
+            for uint::range(0, pcm.len() / 2) |i| {
+                self.mono[i] = pcm[i * 2];
+            }
+            view(self.mono, 0, pcm.len() / 2)
+        } else {
+            pcm
+        };
+
+        match self.meter {
+            Some(ref meter) => {
+                meter.set_format(self.sample_rate as uint, channels);
+                meter.add(pcm);
+            }
+            None => {}
+        }
+        if !self.audible {
+            return;
+        }
+
+        if self.device.is_none() {
+            println(fmt!("sample rate is %d, %u channels", self.sample_rate as int, channels));
+            let rate = match self.resampler {
+                Some(ref resampler) => resampler.out_rate as i32,
+                None => self.sample_rate
+            };
+            let sample_format = ao::SampleFormat(16, rate, channels as i32, ao::Little);
+            self.device = Some(self.ao.open_live(self.ao.default_driver_id(), &sample_format));
+        }
+
+        // Write the bytes, in little-endian.
+        match self.device {
+            Some(ref device) => match self.resampler {
+                Some(ref resampler) => {
+                    resampler.set_input(self.sample_rate as uint, channels);
+                    do resampler.process(pcm) |out| {
+                        device.play(out);
+                    }
+                }
+                None => device.play(pcm)
+            },
+            None => {}
+        }
+    }
+}
+
+// Plays frames stored back to back, resynchronizing after errors.
+fn play_frames(player: &Player, bytes: &[u8]) {
+    let mut bytes = bytes;
+    while bytes.len() > 0 {
+        let frame_size = match player.play_frame(bytes, None) {
+            Some(size) => size,
+            None => match find_sync(view(bytes, 1, bytes.len())) {
+                Some(offset) => offset + 1,
+                None => bytes.len()
+            }
+        };
+        if bytes.len() <= frame_size { break; }
+        bytes = view(bytes, frame_size, bytes.len());
+    }
+}
+
+// Plays an event from a demultiplexer or RTP depacketizer.
+fn play_packet_event(player: &Player, assembler: &FrameAssembler, event: &PacketEvent) {
+    match *event {
+        Payload(payload, pts) => {
+            do assembler.push(payload, pts) |frame, pts| {
+                player.play_frame(frame, pts);
+            }
+        }
+        Discontinuity => {
+            assembler.reset();
+            player.lose();
+        }
+    }
+}
+
+// Internet radio
+//
+// SHOUTcast and Icecast servers speak HTTP/1.x (SHOUTcast answers "ICY 200 OK"). A client that
+// sends "Icy-MetaData: 1" gets a metadata block after every icy-metaint bytes of audio: a length
+// byte counting 16-byte units, then text such as "StreamTitle='...';", padded with zeros.
+
+const ICY_MAX_METADATA: uint = 4080;    // 255 units of 16 bytes
+
+struct IcyReader {
+    // Audio bytes between metadata blocks, and the count left before the next block
+    metaint: uint;
+    mut audio_left: uint;
+
+    // The metadata block being read: its length once the length byte is read, and the bytes so
+    // far
+    mut metadata_size: option<uint>;
+    metadata: [mut u8]/4080;
+    mut metadata_len: uint;
+
+    // The last stream title, which is sent again in every metadata block
+    title: [mut u8]/4080;
+    mut title_len: option<uint>;
+}
+
+fn IcyReader(metaint: uint) -> IcyReader {
+    IcyReader {
+        metaint: metaint,
+        audio_left: metaint,
+        metadata_size: None,
+        metadata: [ mut 0, ..4080 ],
+        metadata_len: 0,
+        title: [ mut 0, ..4080 ],
+        title_len: None
+    }
+}
+
+impl IcyReader {
+    // Adds data from the stream, calling the first function with each run of audio and the
+    // second with each new stream title, which is usually UTF-8 but may be Latin-1.
+    fn push(data: &[u8], audio: fn(&[u8]), title: fn(&TagText)) {
+        let mut data = data;
+        while data.len() > 0 {
+            if self.metaint == 0 || self.audio_left > 0 {
+                let count = if self.metaint == 0 {
+                    data.len()
+                } else {
+                    uint::min(self.audio_left, data.len())
+                };
+                audio(view(data, 0, count));
+                self.audio_left -= uint::min(self.audio_left, count);
+                data = view(data, count, data.len());
+                loop;
+            }
+
+            let size = match self.metadata_size {
+                Some(size) => size,
+                None => {
+                    let size = (data[0] as uint) * 16;
+                    data = view(data, 1, data.len());
+                    self.metadata_size = Some(size);
+                    self.metadata_len = 0;
+                    size
+                }
+            };
+            let count = uint::min(size - self.metadata_len, data.len());
+            for uint::range(0, count) |i| {
+                self.metadata[self.metadata_len + i] = data[i];
+            }
+            self.metadata_len += count;
+            data = view(data, count, data.len());
+
+            if self.metadata_len == size {
+                if size > 0 {
+                    match stream_title(view(self.metadata, 0, size)) {
+                        Some(text) if self.is_new_title(text) => {
+                            for uint::range(0, text.len()) |i| {
+                                self.title[i] = text[i];
+                            }
+                            self.title_len = Some(text.len());
+                            title(&TagText { encoding: TEXT_UTF8, data: text });
+                        }
+                        _ => {}
+                    }
+                }
+                self.metadata_size = None;
+                self.audio_left = self.metaint;
+            }
+        }
+    }
+
+    fn is_new_title(text: &[u8]) -> bool {
+        match self.title_len {
+            Some(len) => !vec::eq(view(self.title, 0, len), text),
+            None => true
+        }
+    }
+}
+
+// Returns the StreamTitle field of a metadata block.
+fn stream_title(text: &a/[u8]) -> option<&a/[u8]> {
+    let mut pos = 0;
+    while pos + 13 <= text.len() {
+        if has_magic(text, pos, "StreamTitle='") {
+            let start = pos + 13;
+            let mut end = start;
+            // The title ends at "';", since it may itself contain quotes.
+            while end < text.len() && text[end] != 0 && !(text[end] == '\'' as u8 &&
+                    (end + 1 == text.len() || text[end + 1] == ';' as u8)) {
+                end += 1;
+            }
+            return Some(view(text, start, end));
+        }
+        pos += 1;
+    }
+    return None;
+}
+
+// Splits an "http://host[:port]/path" URL into its host, port and path.
+fn parse_url(url: String) -> option<(UniqueString, uint, UniqueString)> {
+    if !str::starts_with(url, "http://") {
+        return None;
+    }
+    let rest = str::slice(url, 7, url.len());
+    let (authority, path) = match str::find_char(rest, '/') {
+        Some(pos) => (str::slice(rest, 0, pos), str::slice(rest, pos, rest.len())),
+        None => (copy rest, ~"/")
+    };
+    match str::find_char(authority, ':') {
+        Some(pos) => {
+            match uint::from_str(str::slice(authority, pos + 1, authority.len())) {
+                Some(port) if port < 65536 => {
+                    Some((str::slice(authority, 0, pos), port, path))
+                }
+                _ => None
+            }
+        }
+        None => Some((authority, 80, path))
+    }
+}
+
+// Plays an internet radio stream, printing the stream title whenever it changes.
+fn play_radio(player: &Player, url: String) -> MP2Result<()> {
+    let assembler = FrameAssembler();
+    let audio = |audio: &[u8]| {
+        do assembler.push(audio, None) |frame, pts| {
+            player.play_frame(frame, pts);
+        }
+    };
+    let title = |title: &TagText| println(fmt!("StreamTitle: %s", title.to_str()));
+    match read_radio(url, audio, title) {
+        OK(_) => {}
+        Error(e) => return Error(e)
+    }
+    do assembler.finish |frame, pts| {
+        player.play_frame(frame, pts);
+    }
+    return OK(());
+}
+
+// Reads an internet radio stream until the server closes it, calling the first function with
+// each run of audio and the second with each new stream title.
+fn read_radio(url: String, audio: fn(&[u8]), title: fn(&TagText)) -> MP2Result<()> {
+    let (host, port, path) = match parse_url(url) {
+        Some(parts) => parts,
+        None => return Error("unsupported URL")
+    };
+    let iotask = std::uv::global_loop::get();
+    let address = match std::net::ip::get_addr(host, iotask) {
+        OK(addresses) if addresses.len() > 0 => copy addresses[0],
+        _ => return Error("couldn't resolve host")
+    };
+    let socket = match std::net::tcp::connect(address, port, iotask) {
+        OK(socket) => socket,
+        Error(_) => return Error("couldn't connect")
+    };
+    let socket = std::net::tcp::socket_buf(socket);
+    socket.write_str(fmt!("GET %s HTTP/1.0\r\nHost: %s\r\nUser-Agent: fempeg\r\n", path, host));
+    socket.write_str("Icy-MetaData: 1\r\n\r\n");
+
+    // Read the status line and headers. SHOUTcast answers "ICY 200 OK" instead of HTTP.
+    let status = str::trim_right(socket.read_line());
+    let words = str::split_char(status, ' ');
+    if words.len() < 2 || (!str::starts_with(words[0], "HTTP/1.") && words[0] != ~"ICY") {
+        return Error("not an HTTP response");
+    }
+    if words[1] != ~"200" {
+        return Error(fmt!("server said: %s", status));
+    }
+    let mut metaint = 0;
+    loop {
+        let line = str::trim_right(socket.read_line());
+        if line == ~"" {
+            break;
+        }
+        match str::find_char(line, ':') {
+            Some(pos) if str::to_lower(str::slice(line, 0, pos)) == ~"icy-metaint" => {
+                metaint = uint::from_str(str::trim(str::slice(line, pos + 1, line.len())))
+                    .get_default(0);
+            }
+            Some(pos) if str::to_lower(str::slice(line, 0, pos)) == ~"icy-name" => {
+                println(fmt!("Station: %s", str::trim(str::slice(line, pos + 1, line.len()))));
+            }
+            _ => {}
+        }
+    }
+
+    let icy_reader = IcyReader(metaint);
+    let buffer = [ mut 0u8, ..4096 ];
+    while !socket.eof() {
+        let count = socket.read(buffer, 4096);
+        icy_reader.push(view(buffer, 0, count), audio, title);
+    }
+    return OK(());
+}
+
+// Plays data from an internet radio stream.
+fn play_icy_data(player: &Player, icy_reader: &IcyReader, assembler: &FrameAssembler,
+                 data: &[u8]) {
+    let audio = |audio: &[u8]| {
+        do assembler.push(audio, None) |frame, pts| {
+            player.play_frame(frame, pts);
+        }
+    };
+    let title = |title: &TagText| println(fmt!("StreamTitle: %s", title.to_str()));
+    icy_reader.push(data, audio, title);
+}
+
+// Information display
+
+fn print_tag_field(name: String, field: option<TagText>) {
+    match field {
+        Some(text) => println(fmt!("%s: %s", name, text.to_str())),
+        None => {}
+    }
+}
+
+fn print_info(bytes: &[u8], stream: &MP2Stream) {
+    let tags = Tags(bytes);
+    if tags.id3v2_size > 0 {
+        println(fmt!("ID3v2 tag: %u bytes", tags.id3v2_size));
+    }
+    if tags.ape_size > 0 {
+        println(fmt!("APEv2 tag: %u bytes", tags.ape_size));
+    }
+    if tags.id3v1_present {
+        println("ID3v1 tag: 128 bytes");
+    }
+    print_tag_field("Title", tags.title);
+    print_tag_field("Artist", tags.artist);
+    print_tag_field("Album", tags.album);
+    print_tag_field("Comment", tags.comment);
+    print_tag_field("ReplayGain track gain", tags.track_gain);
+    print_tag_field("ReplayGain track peak", tags.track_peak);
+    print_tag_field("ReplayGain album gain", tags.album_gain);
+    print_tag_field("ReplayGain album peak", tags.album_peak);
+
+    let header = match parse_header(tags.audio) {
+        OK(header) => header,
+        Error(e) => { println(fmt!("Error: %s", e)); return; }
+    };
+    println(fmt!("Sample rate: %d Hz", header.sample_rate() as int));
+    println(fmt!("Bit rate: %d kbit/s", header.bit_rate() as int));
+    println(fmt!("Mode: %s", match header.mode {
+        Stereo => "stereo",
+        JointStereo => "joint stereo",
+        DualChannel => "dual channel",
+        Mono => "mono"
+    }));
+
+    let samples = match read_vbr_info(tags.audio) {
+        Some(vbr) => {
+            println(fmt!("%s header: %u frames, %u bytes%s", match vbr.kind {
+                Xing => "Xing/Info",
+                VBRI => "VBRI"
+            }, vbr.frames, vbr.bytes, if vbr.has_toc { ", seek table" } else { "" }));
+            if vbr.has_gapless_info {
+                println(fmt!("Encoder delay: %u samples, padding: %u samples",
+                             vbr.encoder_delay, vbr.encoder_padding));
+            }
+            vbr.duration_samples()
+        }
+        None => {
+            // Assume a constant bit rate.
+            tags.audio.len() / header.frame_size() * SAMPLES_PER_FRAME
+        }
+    };
+    println(fmt!("Duration: %.3f s", (samples as float) / (header.sample_rate() as float)));
+}
+
+// Seeking
+
+// Returns the offset of the frame nearest to the given time in the audio data, or None if the
+// time is past the end.
+fn seek(audio: &[u8], vbr: &option<VbrInfo>, seconds: float) -> option<uint> {
+    let header = match parse_header(audio) {
+        OK(header) => header,
+        Error(_) => return None
+    };
+    let sample_rate = header.sample_rate() as float;
+    let estimate = match *vbr {
+        Some(ref info) if info.frames > 0 => {
+            let fraction = seconds * sample_rate / (info.duration_samples() as float);
+            match info.seek_offset(fraction) {
+                Some(offset) => offset,
+                None => (fraction * ((audio.len() - info.header_size) as float)) as uint +
+                    info.header_size
+            }
+        }
+        _ => {
+            // Constant bit rate; compute the frame index directly.
+            let frame = (seconds * sample_rate / (SAMPLES_PER_FRAME as float)) as uint;
+            frame * header.frame_size()
+        }
+    };
+    if estimate >= audio.len() {
+        return None;
+    }
+    return match find_sync(view(audio, estimate, audio.len())) {
+        Some(offset) => Some(estimate + offset),
+        None => None
+    };
+}
+
+// DAB output
+
+fn handle_pad_event(event: &PadEvent, slides_path: &option<UniqueString>, slide_count: &mut uint) {
+    match *event {
+        DynamicLabel(charset, text) => {
+            let encoding = if charset == 15 { TEXT_UTF8 } else { TEXT_LATIN1 };
+            println(fmt!("Label: %s", TagText { encoding: encoding, data: text }.to_str()));
+        }
+        ClearLabel => println("Label cleared"),
+        MotObject(content_type, subtype, body) => {
+            println(fmt!("MOT object: type %u/%u, %u bytes", content_type, subtype, body.len()));
+            match *slides_path {
+                Some(ref dir) if content_type == MOT_CONTENT_IMAGE => {
+                    let extension = match subtype {
+                        0 => "gif",
+                        1 => "jpg",
+                        2 => "bmp",
+                        _ => "png"
+                    };
+                    let path = fmt!("%s/slide-%u.%s", *dir, *slide_count, extension);
+                    *slide_count += 1;
+                    match io::file_writer(path, ~[io::Create, io::Truncate]) {
+                        OK(writer) => writer.write(body),
+                        Error(e) => println(e)
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+// Command-line options
+
+struct Options {
+    mut info: bool;
+    mut start: float;   // Seconds
+    mut encoder_delay: option<uint>;
+    mut encoder_padding: option<uint>;
+    mut ancillary_path: option<UniqueString>;
+    mut dab: bool;
+    mut slides_path: option<UniqueString>;
+    mut scf_crc_mode: ScfCrcMode;
+    mut conceal_mode: ConcealMode;
+    mut stream_id: option<u8>;
+    mut pid: option<uint>;
+    mut timestamps: bool;
+    mut port: option<uint>;
+    mut mpa_robust: bool;
+    mut metaint: option<uint>;
+    mut multichannel: bool;
+    mut deemphasis: bool;
+    mut rate: option<uint>;
+    mut quality: ResampleQuality;
+    mut loudness: bool;
+    mut replaygain: option<ReplayGainMode>;
+    mut preamp: float;  // dB
+    mut path: UniqueString;
+}
+
+fn usage(program: String) {
+    println(fmt!("usage: %s [options] file.mp2", program));
+    println(fmt!("       %s gain [--dab] STEPS input.mp2 output.mp2", program));
+    println(fmt!("       %s cut [--start TIME] [--end TIME] input.mp2 output.mp2", program));
+    println(fmt!("       %s join output.mp2 input.mp2 input.mp2...", program));
+    println(fmt!("       %s validate input.mp2", program));
+    println(fmt!("       %s conformance directory", program));
+    println(fmt!("       %s fuzz corpus-directory [iterations]", program));
+    println("options:");
+    println("    --info                 print tags and stream parameters instead of playing");
+    println("    --loudness             measure loudness (EBU R 128) and ReplayGain instead of");
+    println("                           playing");
+    println("    --replaygain track|album");
+    println("                           apply ReplayGain from the tags");
+    println("    --preamp DB            gain to add to ReplayGain");
+    println("    --start SECONDS        start playback at the given time");
+    println("    --delay SAMPLES        encoder delay to trim");
+    println("    --padding SAMPLES      encoder padding to trim");
+    println("    --ancillary FILE       write each frame's ancillary data to a file");
+    println("    --dab                  print DAB dynamic labels and slideshow images");
+    println("    --slides DIRECTORY     save DAB slideshow images (implies --dab)");
+    println("    --scf-crc reuse|attenuate");
+    println("                           check the DAB ScF-CRC and conceal scale factor errors");
+    println("    --conceal repeat|mute|silence");
+    println("                           how to fill in for corrupt frames (default: repeat)");
+    println("    --stream N             play audio stream N (0-31) of a program stream");
+    println("    --pid PID              play the audio on the given PID of a transport stream");
+    println("    --timestamps           print the presentation timestamp of each frame");
+    println("    --port PORT            play the RTP stream sent to the given UDP port");
+    println("    --mpa-robust           RTP packets use the RFC 3119 payload format");
+    println("    --metaint BYTES        the file is a saved internet radio stream with metadata");
+    println("    --multichannel         play MPEG-2 multichannel audio as 5.1");
+    println("    --no-deemphasis        ignore the emphasis flagged in the header");
+    println("    --rate HZ              resample the output to the given rate");
+    println("    --quality low|medium|high");
+    println("                           resampling quality (default: medium)");
+}
+
+fn parse_options(args: &[UniqueString]) -> option<Options> {
+    let options = Options {
+        info: false,
+        start: 0.0,
+        encoder_delay: None,
+        encoder_padding: None,
+        ancillary_path: None,
+        dab: false,
+        slides_path: None,
+        scf_crc_mode: ScfCrcOff,
+        conceal_mode: ConcealRepeat,
+        stream_id: None,
+        pid: None,
+        timestamps: false,
+        port: None,
+        mpa_robust: false,
+        metaint: None,
+        multichannel: false,
+        deemphasis: true,
+        rate: None,
+        quality: ResampleMedium,
+        loudness: false,
+        replaygain: None,
+        preamp: 0.0,
+        path: ~""
+    };
+    let mut i = 1;
+    while i < args.len() {
+        let arg = copy args[i];
+        if arg == ~"--info" {
+            options.info = true;
+        } else if arg == ~"--loudness" {
+            options.loudness = true;
+        } else if arg == ~"--replaygain" && i + 1 < args.len() {
+            i += 1;
+            options.replaygain = match copy args[i] {
+                ~"track" => Some(ReplayGainTrack),
+                ~"album" => Some(ReplayGainAlbum),
+                _ => return None
+            };
+        } else if arg == ~"--preamp" && i + 1 < args.len() {
+            i += 1;
+            let text = if str::starts_with(args[i], "+") {
+                str::slice(args[i], 1, args[i].len())
+            } else {
+                copy args[i]
+            };
+            match float::from_str(text) {
+                Some(preamp) => options.preamp = preamp,
+                None => return None
+            }
+        } else if arg == ~"--start" && i + 1 < args.len() {
+            i += 1;
+            match float::from_str(args[i]) {
+                Some(seconds) if seconds >= 0.0 => options.start = seconds,
+                _ => return None
+            }
+        } else if (arg == ~"--delay" || arg == ~"--padding") && i + 1 < args.len() {
+            i += 1;
+            let samples = uint::from_str(args[i]);
+            if samples.is_none() {
+                return None;
+            }
+            if arg == ~"--delay" {
+                options.encoder_delay = samples;
+            } else {
+                options.encoder_padding = samples;
+            }
+        } else if arg == ~"--ancillary" && i + 1 < args.len() {
+            i += 1;
+            options.ancillary_path = Some(copy args[i]);
+        } else if arg == ~"--dab" {
+            options.dab = true;
+        } else if arg == ~"--slides" && i + 1 < args.len() {
+            i += 1;
+            options.dab = true;
+            options.slides_path = Some(copy args[i]);
+        } else if arg == ~"--scf-crc" && i + 1 < args.len() {
+            i += 1;
+            options.scf_crc_mode = match copy args[i] {
+                ~"reuse" => ScfCrcReuse,
+                ~"attenuate" => ScfCrcAttenuate,
+                _ => return None
+            };
+        } else if arg == ~"--conceal" && i + 1 < args.len() {
+            i += 1;
+            options.conceal_mode = match copy args[i] {
+                ~"repeat" => ConcealRepeat,
+                ~"mute" => ConcealMute,
+                ~"silence" => ConcealSilence,
+                _ => return None
+            };
+        } else if arg == ~"--stream" && i + 1 < args.len() {
+            i += 1;
+            match uint::from_str(args[i]) {
+                Some(n) if n < 32 => options.stream_id = Some(AUDIO_STREAM_FIRST + (n as u8)),
+                _ => return None
+            }
+        } else if arg == ~"--pid" && i + 1 < args.len() {
+            i += 1;
+            match uint::from_str(args[i]) {
+                Some(pid) if pid < NULL_PID => options.pid = Some(pid),
+                _ => return None
+            }
+        } else if arg == ~"--timestamps" {
+            options.timestamps = true;
+        } else if arg == ~"--port" && i + 1 < args.len() {
+            i += 1;
+            match uint::from_str(args[i]) {
+                Some(port) if port < 65536 => options.port = Some(port),
+                _ => return None
+            }
+        } else if arg == ~"--mpa-robust" {
+            options.mpa_robust = true;
+        } else if arg == ~"--rate" && i + 1 < args.len() {
+            i += 1;
+            match uint::from_str(args[i]) {
+                Some(rate) if rate >= 8000 && rate <= 192000 => options.rate = Some(rate),
+                _ => return None
+            }
+        } else if arg == ~"--quality" && i + 1 < args.len() {
+            i += 1;
+            options.quality = match copy args[i] {
+                ~"low" => ResampleLow,
+                ~"medium" => ResampleMedium,
+                ~"high" => ResampleHigh,
+                _ => return None
+            };
+        } else if arg == ~"--no-deemphasis" {
+            options.deemphasis = false;
+        } else if arg == ~"--multichannel" {
+            options.multichannel = true;
+        } else if arg == ~"--metaint" && i + 1 < args.len() {
+            i += 1;
+            match uint::from_str(args[i]) {
+                Some(metaint) => options.metaint = Some(metaint),
+                None => return None
+            }
+        } else if str::starts_with(arg, "--") || options.path != ~"" {
+            return None;
+        } else {
+            options.path = arg;
+        }
+        i += 1;
+    }
+    if options.path == ~"" {
+        return None;
+    }
+    return Some(options);
+}
+
+// Entry point
+
+fn main(args: ~[UniqueString]) {
+    if args.len() > 1 && args[1] == ~"gain" {
+        run_gain(&MP2Context(), args);
+        return;
+    }
+    if args.len() > 1 && args[1] == ~"cut" {
+        run_cut(args);
+        return;
+    }
+    if args.len() > 1 && args[1] == ~"join" {
+        run_join(args);
+        return;
+    }
+    if args.len() > 1 && args[1] == ~"validate" {
+        run_validate(&MP2Context(), args);
+        return;
+    }
+    if args.len() > 1 && args[1] == ~"conformance" {
+        run_conformance(&MP2Context(), args);
+        return;
+    }
+    if args.len() > 1 && args[1] == ~"fuzz" {
+        run_fuzz(args);
+        return;
+    }
+
+    let options = match parse_options(args) {
+        Some(options) => options,
+        None => { usage(args[0]); return; }
+    };
+
+    // Internet radio streams are read from the network as they play.
+    let is_radio = str::starts_with(options.path, "http://");
+    let result = if is_radio { OK(~[]) } else { io::read_whole_file(options.path) };
+    let bytes = match result {
+        OK(_)    => unwrap(result),
+        Error(e) => { println(e); return; }
+    };
+    let bytes = view(bytes, 0, bytes.len());
+
+    let context = MP2Context();
+    let stream = MP2Stream(&context);
+    stream.scf_crc_mode = options.scf_crc_mode;
+    stream.conceal_mode = options.conceal_mode;
+    stream.deemphasis = options.deemphasis;
+    stream.deemph_by_caller = options.multichannel;
+    if options.info && !is_radio {
+        print_info(bytes, &stream);
+        return;
+    }
+
+    let ancillary_writer = match options.ancillary_path {
+        Some(ref path) => match io::file_writer(*path, ~[io::Create, io::Truncate]) {
+            OK(writer) => Some(writer),
+            Error(e) => { println(e); return; }
+        },
+        None => None
+    };
+
+    let ao = ao::AO();
+    let player = Player {
+        stream: &stream,
+        ao: &ao,
+        pcm: [ mut 0, ..2304 ],     // FIXME: Rust compiler should accept (SAMPLES_PER_FRAME*2).
+        multichannel: if options.multichannel { Some(McDecoder()) } else { None },
+        mc_pcm: [ mut 0, ..6912 ],
+        device: None,
+        sample_rate: 0,
+        channels: 0,
+        mono: [ mut 0, ..1152 ],
+        trimmer: None,
+        resampler: match options.rate {
+            Some(rate) => Some(Resampler(rate, options.quality)),
+            None => None
+        },
+        meter: if options.loudness { Some(LoudnessMeter()) } else { None },
+        audible: !options.loudness,
+        ancillary_writer: ancillary_writer,
+        ancillary: [ mut 0, ..1729 ],
+        pad_decoder: if options.dab { Some(PadDecoder()) } else { None },
+        slides_path: copy options.slides_path,
+        slide_count: 0,
+        show_timestamps: options.timestamps,
+        next_pts: None,
+        lost: false
+    };
+
+    if is_radio {
+        match play_radio(&player, options.path) {
+            OK(_) => {}
+            Error(e) => { println(e); return; }
+        }
+        player.finish();
+        return;
+    }
+    match options.metaint {
+        Some(metaint) => {
+            // Saved internet radio stream
+            let assembler = FrameAssembler();
+            play_icy_data(&player, &IcyReader(metaint), &assembler, bytes);
+            do assembler.finish |frame, pts| {
+                player.play_frame(frame, pts);
+            }
+            player.finish();
+            return;
+        }
+        None => {}
+    }
+    if pcap_byte_order(bytes).is_some() {
+        // RTP packet capture
+        let assembler = FrameAssembler();
+        let depacketizer = RtpDepacketizer(options.mpa_robust);
+        let result = do read_pcap(bytes, options.port) |datagram| {
+            do depacketizer.push(datagram) |event| {
+                play_packet_event(&player, &assembler, event);
+            }
+        };
+        match result {
+            OK(_) => {}
+            Error(e) => { println(e); return; }
+        }
+        do depacketizer.finish |event| {
+            play_packet_event(&player, &assembler, event);
+        }
+        do assembler.finish |frame, pts| {
+            player.play_frame(frame, pts);
+        }
+        player.finish();
+        return;
+    }
+
+    let tags = Tags(bytes);
+    let audio = tags.audio;
+    match options.replaygain {
+        Some(mode) => match replaygain(&tags, mode, options.preamp) {
+            Some(gain) => {
+                println(fmt!("ReplayGain: %.2f dB", gain));
+                stream.set_gain(gain);
+            }
+            None => println("no ReplayGain tags")
+        },
+        None => {}
+    }
+    match ts_packet_size(audio) {
+        Some(packet_size) => {
+            // MPEG-2 transport stream
+            let assembler = FrameAssembler();
+            let demuxer = TsDemuxer(options.pid);
+            do demuxer.demux(audio, packet_size) |event| {
+                play_packet_event(&player, &assembler, event);
+            }
+            do assembler.finish |frame, pts| {
+                player.play_frame(frame, pts);
+            }
+            player.finish();
+            return;
+        }
+        None => {}
+    }
+    if bytes.len() >= 8 && read_u32_be(bytes, 4) == fourcc("ftyp") {
+        // MP4. Chunk offsets are from the start of the file, so tags mustn't be stripped.
+        let assembler = FrameAssembler();
+        let result = do demux_mp4(bytes) |sample, pts| {
+            do assembler.push(sample, pts) |frame, pts| {
+                player.play_frame(frame, pts);
+            }
+        };
+        match result {
+            OK(_) => {}
+            Error(e) => { println(e); return; }
+        }
+        do assembler.finish |frame, pts| {
+            player.play_frame(frame, pts);
+        }
+        player.finish();
+        return;
+    }
+    if audio.len() >= 4 && read_u32_be(audio, 0) == EBML_HEADER_ID {
+        // Matroska
+        let assembler = FrameAssembler();
+        let demuxer = MatroskaDemuxer();
+        let result = do demuxer.demux(audio) |block, pts| {
+            do assembler.push(block, pts) |frame, pts| {
+                player.play_frame(frame, pts);
+            }
+        };
+        match result {
+            OK(_) => {}
+            Error(e) => { println(e); return; }
+        }
+        do assembler.finish |frame, pts| {
+            player.play_frame(frame, pts);
+        }
+        player.finish();
+        return;
+    }
+    if is_start_code(audio, 0) && audio[3] == PACK_START_CODE {
+        // MPEG-1 system stream or MPEG-2 program stream
+        let assembler = FrameAssembler();
+        do demux_program_stream(audio, options.stream_id) |payload, pts| {
+            do assembler.push(payload, pts) |frame, pts| {
+                player.play_frame(frame, pts);
+            }
+        }
+        do assembler.finish |frame, pts| {
+            player.play_frame(frame, pts);
+        }
+        player.finish();
+        return;
+    }
+
+    // Skip over the VBR header frame, then seek.
+    let vbr = read_vbr_info(audio);
+    let mut start = match vbr {
+        Some(ref info) => info.header_size,
+        None => 0
+    };
+    if options.start > 0.0 {
+        start = match seek(audio, &vbr, options.start) {
+            Some(offset) => offset,
+            None => return
+        };
+    }
+    start += match find_sync(view(audio, start, audio.len())) {
+        Some(offset) => offset,
+        None => { println("no MPEG-Audio Layer II frames found"); return; }
+    };
+
+    // Set up gapless trimming if the encoder delay is known. Explicit options override the
+    // VBR header. Trimming the start doesn't apply when seeking.
+    let (header_delay, header_padding, frames) = match vbr {
+        Some(ref info) if info.has_gapless_info => {
+            (Some(info.encoder_delay), Some(info.encoder_padding), info.frames)
+        }
+        Some(ref info) => (None, None, info.frames),
+        None => (None, None, 0)
+    };
+    let delay = if options.encoder_delay.is_some() { options.encoder_delay } else { header_delay };
+    let padding = if options.encoder_padding.is_some() {
+        options.encoder_padding
+    } else {
+        header_padding
+    };
+    player.trimmer = match delay {
+        Some(delay) if options.start == 0.0 => Some(Trimmer(delay, padding.get_default(0), frames)),
+        _ => None
+    };
+
+    play_frames(&player, view(audio, start, audio.len()));
+    player.finish();
+}
+
+#[cfg(test)]
+mod tests {
+    import std::net::ip;
+    import std::net::tcp;
+
+    // First port tried for the stand-in radio server, and how many ports to try
+    const FIRST_TEST_PORT: uint = 48000;
+    const TEST_PORTS: uint = 1000;
+
+    // Answers one connection with the given response, like a radio server, and returns the
+    // port it listens on. std::net::tcp can't say which port it bound when given port 0, so
+    // each port from FIRST_TEST_PORT is tried until one is free; tests running at the same
+    // time each get their own.
+    fn serve_once(response: ~[u8]) -> uint {
+        let ready = comm::port();
+        let ready_chan = comm::chan(ready);
+        do task::spawn {
+            let iotask = std::uv::global_loop::get();
+            let address = ip::v4::parse_addr("127.0.0.1");
+            let mut port = FIRST_TEST_PORT;
+            loop {
+                let this_port = port;
+                let response = copy response;
+                let result = tcp::listen(copy address, this_port, 1, iotask,
+                    |_kill| comm::send(ready_chan, Some(this_port)),
+                    |connection, kill| {
+                        let socket = tcp::socket_buf(result::unwrap(tcp::accept(connection)));
+                        while str::trim_right(socket.read_line()) != ~"" {}
+                        socket.write(response);
+                        comm::send(kill, None);
+                    });
+                if result.is_ok() {
+                    break;
+                }
+                port += 1;
+                if port == FIRST_TEST_PORT + TEST_PORTS {
+                    comm::send(ready_chan, None);
+                    break;
+                }
+            }
+        }
+        return comm::recv(ready).get();
+    }
+
+    // Makes a metadata block of 32 bytes.
+    fn metadata_block(text: String) -> ~[u8] {
+        let mut block = ~[ 2u8 ] + str::bytes(text);
+        while block.len() < 33 {
+            block += ~[ 0u8 ];
+        }
+        return block;
+    }
+
+    #[test]
+    fn radio_titles_are_reported_when_they_change() {
+        let mut response = str::bytes("ICY 200 OK\r\nicy-metaint: 4\r\n\r\n");
+        for [ "StreamTitle='One';", "StreamTitle='One';", "StreamTitle='Two';" ].each |text| {
+            response += ~[ 1u8, 2, 3, 4 ] + metadata_block(*text);
+        }
+        let port = serve_once(response);
+
+        let mut audio = 0;
+        let mut titles = ~[];
+        let result = read_radio(fmt!("http://127.0.0.1:%u/", port),
+                                |data| audio += data.len(),
+                                |title| titles += ~[ title.to_str() ]);
+        assert result.is_ok();
+        assert audio == 12;
+        assert titles == ~[ ~"One", ~"Two" ];
+    }
+
+    // Resamples 0.1 s of a half-scale sine with the high quality filter.
+    fn resample_tone(in_rate: uint, out_rate: uint, frequency: float) -> ~[i16] {
+        let resampler = Resampler(out_rate, ResampleHigh);
+        resampler.set_input(in_rate, 1);
+        let step = 2.0 * float::consts::pi * frequency / (in_rate as float);
+        let input = do vec::from_fn(in_rate / 10) |i| {
+            (16384.0 * float::sin(step * (i as float))) as i16
+        };
+        let mut output = ~[];
+        do resampler.process(input) |chunk| { output += vec::from_slice(chunk); }
+        do resampler.finish |chunk| { output += vec::from_slice(chunk); }
+        return output;
+    }
+
+    // Returns the level of the given frequency in resampled output, and the level of all of
+    // the output, in dB relative to the half-scale input. The filter's start-up and run-out are
+    // skipped.
+    fn tone_levels(output: &[i16], rate: uint, frequency: float) -> (float, float) {
+        let samples = view(output, 200, output.len() - 200);
+        let step = 2.0 * float::consts::pi * frequency / (rate as float);
+        let mut in_phase = 0.0;
+        let mut quadrature = 0.0;
+        let mut energy = 0.0;
+        for uint::range(0, samples.len()) |i| {
+            let sample = (samples[i] as float) / 16384.0;
+            in_phase += sample * cos(step * (i as float));
+            quadrature += sample * float::sin(step * (i as float));
+            energy += sample * sample;
+        }
+        let count = samples.len() as float;
+        let amplitude = 2.0 * float::sqrt(in_phase * in_phase + quadrature * quadrature) / count;
+        let rms = float::sqrt(2.0 * energy / count);
+        return (20.0 * float::log10(amplitude), 20.0 * float::log10(rms));
+    }
+
+    #[test]
+    fn resampler_passband_is_flat() {
+        // Up to about 90% of the cutoff, including tones close to it, within 0.1 dB.
+        for [ 1000.0, 10000.0, 18000.0 ].each |frequency| {
+            let (level, _) = tone_levels(resample_tone(48000, 44100, *frequency), 44100,
+                                         *frequency);
+            assert float::abs(level) < 0.1;
+        }
+        let (level, _) = tone_levels(resample_tone(48000, 32000, 13000.0), 32000, 13000.0);
+        assert float::abs(level) < 0.1;
+    }
+
+    #[test]
+    fn resampler_rejects_tones_above_the_output_nyquist_frequency() {
+        // These would alias to 20.6 kHz and 12 kHz.
+        let (_, level) = tone_levels(resample_tone(48000, 44100, 23500.0), 44100, 23500.0);
+        assert level < -55.0;
+        let (_, level) = tone_levels(resample_tone(48000, 32000, 20000.0), 32000, 20000.0);
+        assert level < -70.0;
+    }
+
+    // Reads a file from the fuzzing seed corpus.
+    fn corpus_file(name: String) -> ~[u8] {
+        return result::unwrap(io::read_whole_file(fmt!("fuzz/corpus/%s", name)));
+    }
+
+    // Decodes each frame found in the data in turn, calling the function with the result and
+    // output of each.
+    fn decode_frames(stream: &MP2Stream, data: &[u8], f: fn(MP2Result<i32>, &[i16])) {
+        let pcm = [ mut 0i16, ..2304 ];
+        let mut pos = 0;
+        loop {
+            pos += match find_sync(view(data, pos, data.len())) {
+                Some(offset) => offset,
+                None => break
+            };
+            let result = stream.decode_frame(view(data, pos, data.len()), pcm);
+            f(result, pcm);
+            pos += match result {
+                OK(frame_size) => frame_size as uint,
+                Error(_) => 1
+            };
+        }
+    }
+
+    #[test]
+    fn silent_frames_decode_to_silence() {
+        let context = MP2Context();
+        let stream = MP2Stream(&context);
+        let mut frames = 0;
+        do decode_frames(&stream, corpus_file("silent-stereo-48k.mp2")) |result, pcm| {
+            assert result == OK(576);
+            assert vec::all(pcm, |sample| *sample == 0);
+            frames += 1;
+        }
+        assert frames == 4;
+        assert stream.format == AudioFormat { sample_rate: 48000, channels: 2 };
+    }
+
+    #[test]
+    fn frames_decode_in_every_mode() {
+        let context = MP2Context();
+        for [ ("mono-32k.mp2", 4, 32000, 1), ("joint-stereo-44k-crc.mp2", 4, 44100, 2),
+              ("dual-channel-384k.mp2", 2, 48000, 2), ("junk-between-frames.mp2", 2, 48000, 2)
+            ].each |test| {
+            let (name, count, sample_rate, channels) = *test;
+            let stream = MP2Stream(&context);
+            let mut frames = 0;
+            do decode_frames(&stream, corpus_file(name)) |result, _pcm| {
+                assert result.is_ok();
+                frames += 1;
+            }
+            assert frames == count;
+            assert stream.format == AudioFormat { sample_rate: sample_rate, channels: channels };
+        }
+    }
+
+    // Returns the largest magnitude of the samples.
+    fn peak(pcm: &[i16]) -> int {
+        let mut peak = 0;
+        for pcm.each |sample| {
+            peak = int::max(peak, int::abs(*sample as int));
+        }
+        return peak;
+    }
+
+    #[test]
+    fn concealment_fades_out_the_previous_frame() {
+        let context = MP2Context();
+        let stream = MP2Stream(&context);
+        stream.conceal_mode = ConcealRepeat;
+        let pcm = [ mut 0i16, ..2304 ];
+        let mut last_peak = 0;
+        do decode_frames(&stream, corpus_file("random-stereo-48k.mp2")) |result, pcm| {
+            assert result.is_ok();
+            last_peak = peak(pcm);
+        }
+        assert last_peak > 0;
+
+        // Each concealed frame is no louder than the one before, and after the fade and the
+        // filterbank's overlap there's silence.
+        for (CONCEAL_FADE_FRAMES + 2).times {
+            stream.conceal_frame(pcm);
+            let concealed_peak = peak(pcm);
+            assert concealed_peak <= last_peak;
+            last_peak = concealed_peak;
+        }
+        assert last_peak == 0;
+    }
+
+    #[test]
+    fn truncated_frame_is_rejected() {
+        let context = MP2Context();
+        let stream = MP2Stream(&context);
+        let pcm = [ mut 0i16, ..2304 ];
+        assert stream.decode_frame(corpus_file("truncated.mp2"), pcm) == Error("truncated frame");
+    }
+
+    #[test]
+    fn free_format_frame_is_rejected() {
+        let context = MP2Context();
+        let stream = MP2Stream(&context);
+        let pcm = [ mut 0i16, ..2304 ];
+        assert stream.decode_frame(corpus_file("free-format.mp2"), pcm) ==
+            Error("invalid bit rate or 'free format'");
+    }
+
+    #[test]
+    fn ape_items_with_corrupt_keys_are_skipped() {
+        let data = corpus_file("ape-bad-key.mp2");
+        let tags = Tags(data);
+        assert tags.ape_size > 0;
+        assert tags.title.get().to_str() == ~"Good key";
+        assert tags.track_gain.is_none();
+    }
+
+    #[test]
+    fn corpus_files_decode_without_failing() {
+        for os::list_dir("fuzz/corpus").each |name| {
+            fuzz_all(corpus_file(*name));
+        }
+    }
+
+    #[test]
+    fn conformance_streams_are_compliant() {
+        // The conformance bitstreams can't be redistributed. Put them in test-data, each beside
+        // its reference PCM, to run this.
+        if !os::path_exists("test-data") {
+            return;
+        }
+        let context = MP2Context();
+        let mut tested = 0;
+        for os::list_dir("test-data").each |name| {
+            let reference_path = match conformance_reference("test-data", *name) {
+                Some(path) if os::path_exists(path) => path,
+                _ => loop
+            };
+            let audio = result::unwrap(io::read_whole_file(fmt!("test-data/%s", *name)));
+            let reference = result::unwrap(io::read_whole_file(reference_path));
+            let result = check_conformance(&context, view(audio, 0, audio.len()),
+                                           view(reference, 0, reference.len()));
+            assert result.is_ok();
+            assert result.get().accuracy() != NotCompliant;
+            tested += 1;
+        }
+        assert tested > 0;
+    }
+}